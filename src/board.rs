@@ -6,7 +6,7 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
 pub enum Player {
     #[default]
     O,
@@ -38,56 +38,161 @@ impl Display for Player {
 // 2: 3 4 5
 // 1: 0 1 2
 //    A B C : file
-#[derive(Debug, Default, PartialEq, PartialOrd, Clone, Copy)]
-pub struct TileId(u8);
-impl TileId {
-    pub const A1: TileId = TileId(0);
-    pub const B1: TileId = TileId(1);
-    pub const C1: TileId = TileId(2);
-    pub const A2: TileId = TileId(3);
-    pub const B2: TileId = TileId(4);
-    pub const C2: TileId = TileId(5);
-    pub const A3: TileId = TileId(6);
-    pub const B3: TileId = TileId(7);
-    pub const C3: TileId = TileId(8);
+//
+// Larger boards continue the same way, files D, E, ... and ranks 4, 5, ...
+// with tiles numbered row by row starting from A1.
+#[derive(Debug, Default, PartialEq, Eq, Hash, PartialOrd, Clone, Copy, Serialize, Deserialize)]
+pub struct TileId {
+    file: u8,
+    rank: u8,
 }
 
-impl FromStr for TileId {
-    type Err = ();
+impl TileId {
+    pub const A1: TileId = TileId::new(0, 0);
+    pub const B1: TileId = TileId::new(1, 0);
+    pub const C1: TileId = TileId::new(2, 0);
+    pub const A2: TileId = TileId::new(0, 1);
+    pub const B2: TileId = TileId::new(1, 1);
+    pub const C2: TileId = TileId::new(2, 1);
+    pub const A3: TileId = TileId::new(0, 2);
+    pub const B3: TileId = TileId::new(1, 2);
+    pub const C3: TileId = TileId::new(2, 2);
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "a1" | "A1" => Ok(TileId::A1),
-            "a2" | "A2" => Ok(TileId::A2),
-            "a3" | "A3" => Ok(TileId::A3),
-            "b1" | "B1" => Ok(TileId::B1),
-            "b2" | "B2" => Ok(TileId::B2),
-            "b3" | "B3" => Ok(TileId::B3),
-            "c1" | "C1" => Ok(TileId::C1),
-            "c2" | "C2" => Ok(TileId::C2),
-            "c3" | "C3" => Ok(TileId::C3),
-            _ => Err(()),
-        }
+    // Both 0-based, file 0 is A and rank 0 is rank 1
+    pub const fn new(file: u8, rank: u8) -> TileId {
+        TileId { file, rank }
+    }
+
+    pub fn file(self) -> u8 {
+        self.file
+    }
+
+    pub fn rank(self) -> u8 {
+        self.rank
     }
 }
 
-impl TryFrom<u8> for TileId {
-    type Error = ();
+impl Display for TileId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", (b'a' + self.file) as char, self.rank + 1)
+    }
+}
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            value @ 0..=8 => Ok(TileId(value)),
-            _ => Err(()),
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("unknown tile {0:?}")]
+pub struct ParseTileError(String);
+
+// Accepts algebraic names (b2, d4) as well as plain english positions on a
+// 3×3 board (center, top-left), see the layout above. Whether the tile is
+// actually on the board is up to whoever uses it.
+impl FromStr for TileId {
+    type Err = ParseTileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_lowercase().replace([' ', '_'], "-");
+        match name.as_str() {
+            "bottom-left" => Ok(TileId::A1),
+            "left" | "middle-left" | "center-left" => Ok(TileId::A2),
+            "top-left" => Ok(TileId::A3),
+            "bottom" | "bottom-middle" | "bottom-center" => Ok(TileId::B1),
+            "center" | "middle" => Ok(TileId::B2),
+            "top" | "top-middle" | "top-center" => Ok(TileId::B3),
+            "bottom-right" => Ok(TileId::C1),
+            "right" | "middle-right" | "center-right" => Ok(TileId::C2),
+            "top-right" => Ok(TileId::C3),
+            _ => {
+                let mut chars = name.chars();
+                let file = chars
+                    .next()
+                    .filter(|c| c.is_ascii_lowercase())
+                    .map(|c| c as u8 - b'a');
+                let rank = chars.as_str().parse::<u8>().ok().filter(|&rank| rank > 0);
+                match (file, rank) {
+                    (Some(file), Some(rank)) => Ok(TileId::new(file, rank - 1)),
+                    _ => Err(ParseTileError(s.to_string())),
+                }
+            }
         }
     }
 }
 
-#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+// Boards are square, from 1×1 up to this many tiles a side
+pub const MAX_BOARD_SIZE: u8 = 15;
+
+// Goes over the wire in its compact form, see `to_compact`
+#[derive(Debug, PartialEq, Clone)]
 pub struct Board {
-    tiles: [Option<Player>; 9],
+    size: u8,
+    // Row by row, starting from A1
+    tiles: Vec<Option<Player>>,
 }
 
 impl Board {
+    pub fn new(size: u8) -> Board {
+        assert!(
+            (1..=MAX_BOARD_SIZE).contains(&size),
+            "board size must be between 1 and {MAX_BOARD_SIZE}"
+        );
+        Board {
+            size,
+            tiles: vec![None; size as usize * size as usize],
+        }
+    }
+
+    pub fn size(&self) -> u8 {
+        self.size
+    }
+
+    pub fn tile_count(&self) -> u8 {
+        self.tiles.len() as u8
+    }
+
+    pub fn contains(&self, tile: TileId) -> bool {
+        tile.file < self.size && tile.rank < self.size
+    }
+
+    // The tile numbered `index` in the layout above, as used on the wire, e.g.
+    // in Request::PlayTurn
+    pub fn tile_at(&self, index: u8) -> Option<TileId> {
+        (index < self.tile_count()).then(|| TileId::new(index % self.size, index / self.size))
+    }
+
+    pub fn index_of(&self, tile: TileId) -> Option<u8> {
+        self.contains(tile)
+            .then(|| tile.rank * self.size + tile.file)
+    }
+
+    // One character per tile, X, O or - if empty, row by row from A1, so
+    // "X-O--X---" on a 3×3 board. The size is implied by the length.
+    pub fn to_compact(&self) -> String {
+        self.tiles
+            .iter()
+            .map(|tile| match tile {
+                Some(Player::O) => 'O',
+                Some(Player::X) => 'X',
+                None => '-',
+            })
+            .collect()
+    }
+
+    pub fn from_compact(s: &str) -> Result<Board, ParseBoardError> {
+        let tiles = s
+            .chars()
+            .map(|c| match c {
+                'O' => Ok(Some(Player::O)),
+                'X' => Ok(Some(Player::X)),
+                '-' => Ok(None),
+                _ => Err(ParseBoardError(format!("bad tile {c:?}"))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let size = (1..=MAX_BOARD_SIZE)
+            .find(|&size| size as usize * size as usize == tiles.len())
+            .ok_or_else(|| {
+                ParseBoardError(format!("{} tiles don't make a square board", tiles.len()))
+            })?;
+        Ok(Board { size, tiles })
+    }
+
     pub fn mark(&mut self, tile: TileId, player: Player) {
         self[tile] = Some(player);
     }
@@ -95,36 +200,390 @@ impl Board {
     pub fn mark_count(&self) -> u8 {
         self.tiles.iter().flatten().count() as u8
     }
+
+    pub fn is_full(&self) -> bool {
+        self.tiles.iter().all(Option::is_some)
+    }
+
+    // Every straight run of `len` tiles: along ranks, files and both diagonals
+    pub fn lines(&self, len: u8) -> Vec<Vec<TileId>> {
+        let n = self.size as i16;
+        let len = len as i16;
+        let mut lines = Vec::new();
+        if len == 0 || len > n {
+            return lines;
+        }
+
+        for (df, dr) in [(1, 0), (0, 1), (1, 1), (1, -1)] {
+            for file in 0..n {
+                for rank in 0..n {
+                    let (end_file, end_rank) = (file + df * (len - 1), rank + dr * (len - 1));
+                    if !(0..n).contains(&end_file) || !(0..n).contains(&end_rank) {
+                        continue;
+                    }
+                    lines.push(
+                        (0..len)
+                            .map(|i| TileId::new((file + df * i) as u8, (rank + dr * i) as u8))
+                            .collect(),
+                    );
+                }
+            }
+        }
+        lines
+    }
+
+    // All completed lines of `len` for a player. A legal board has at most one
+    // winner, but that player may complete two lines with a single mark.
+    pub fn winning_lines_for(&self, player: Player, len: u8) -> Vec<Vec<TileId>> {
+        self.lines(len)
+            .into_iter()
+            .filter(|line| line.iter().all(|&tile| self[tile] == Some(player)))
+            .collect()
+    }
+
+    // Same as Display, but the last played tile is drawn in lowercase
+    pub fn render_with_last(&self, last: Option<TileId>) -> String {
+        self.render_highlighted(last.as_slice())
+    }
+
+    // Same as Display, but the given tiles are drawn in lowercase
+    pub fn render_highlighted(&self, tiles: &[TileId]) -> String {
+        let mut out = String::new();
+        self.render(&mut out, tiles)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    fn render(&self, f: &mut impl std::fmt::Write, highlight: &[TileId]) -> std::fmt::Result {
+        // Wide enough for the highest rank number
+        let width = self.size.to_string().len();
+        for (rank, tiles) in self
+            .tiles
+            .chunks_exact(self.size as usize)
+            .enumerate()
+            .rev()
+        {
+            write!(f, "{:>width$}│ ", rank + 1)?;
+            for (file, tile) in tiles.iter().enumerate() {
+                let highlighted = highlight.contains(&TileId::new(file as u8, rank as u8));
+                match tile {
+                    Some(Player::O) if highlighted => write!(f, "o")?,
+                    Some(Player::X) if highlighted => write!(f, "x")?,
+                    Some(player) => write!(f, "{player}")?,
+                    None => write!(f, "-")?,
+                };
+            }
+
+            writeln!(f)?;
+        }
+        write!(
+            f,
+            "{:width$}╰{}\n{:width$}  ",
+            "",
+            "─".repeat(self.size as usize + 2),
+            ""
+        )?;
+        for file in 0..self.size {
+            write!(f, "{}", (b'A' + file) as char)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Board::new(3)
+    }
+}
+
+impl Serialize for Board {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_compact())
+    }
+}
+
+impl<'de> Deserialize<'de> for Board {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let compact = String::deserialize(deserializer)?;
+        Board::from_compact(&compact).map_err(serde::de::Error::custom)
+    }
 }
 
 impl Index<TileId> for Board {
     type Output = Option<Player>;
 
     fn index(&self, tile: TileId) -> &Self::Output {
-        &self.tiles[tile.0 as usize]
+        let index = self.index_of(tile).expect("tile is not on the board");
+        &self.tiles[index as usize]
     }
 }
 
 impl IndexMut<TileId> for Board {
-    fn index_mut(&mut self, index: TileId) -> &mut Self::Output {
-        &mut self.tiles[index.0 as usize]
+    fn index_mut(&mut self, tile: TileId) -> &mut Self::Output {
+        let index = self.index_of(tile).expect("tile is not on the board");
+        &mut self.tiles[index as usize]
+    }
+}
+
+impl<'a> IntoIterator for &'a Board {
+    type Item = (TileId, Option<Player>);
+    type IntoIter = Tiles<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Tiles {
+            board: self,
+            next: 0,
+        }
+    }
+}
+
+// Every tile on a board along with its mark, row by row from A1
+#[derive(Debug, Clone)]
+pub struct Tiles<'a> {
+    board: &'a Board,
+    next: u8,
+}
+
+impl Iterator for Tiles<'_> {
+    type Item = (TileId, Option<Player>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tile = self.board.tile_at(self.next)?;
+        self.next += 1;
+        Some((tile, self.board[tile]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.board.tile_count().saturating_sub(self.next) as usize;
+        (left, Some(left))
     }
 }
 
+impl ExactSizeIterator for Tiles<'_> {}
+
 impl Display for Board {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (i, rank) in self.tiles.chunks_exact(3).enumerate().rev() {
-            write!(f, "{}│ ", i + 1)?;
-            for tile in rank {
-                match tile {
-                    Some(player) => write!(f, "{player}")?,
-                    None => write!(f, "-")?,
-                };
+        self.render(f, &[])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("invalid board: {0}")]
+pub struct ParseBoardError(String);
+
+// Reads a board back from its Display output, ranks from the highest down with
+// `-` for empty tiles. Highlighted (lowercase) marks are accepted too. The
+// rank labels are checked, the rule and file letters underneath are optional.
+impl FromStr for Board {
+    type Err = ParseBoardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rows = Vec::new();
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let Some((label, tiles)) = line.split_once('│') else {
+                let is_footer = line.starts_with('╰')
+                    || line.bytes().enumerate().all(|(i, c)| c == b'A' + i as u8);
+                if is_footer {
+                    continue;
+                }
+                return Err(ParseBoardError(format!("unexpected line {line:?}")));
+            };
+            let rank = label
+                .trim()
+                .parse::<u8>()
+                .map_err(|_| ParseBoardError(format!("bad rank label {label:?}")))?;
+            let tiles = tiles
+                .trim()
+                .chars()
+                .map(|c| match c {
+                    'O' | 'o' => Ok(Some(Player::O)),
+                    'X' | 'x' => Ok(Some(Player::X)),
+                    '-' => Ok(None),
+                    _ => Err(ParseBoardError(format!("bad tile {c:?} on rank {rank}"))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            rows.push((rank, tiles));
+        }
+
+        let size = rows.len();
+        if !(1..=MAX_BOARD_SIZE as usize).contains(&size) {
+            return Err(ParseBoardError(format!(
+                "expected between 1 and {MAX_BOARD_SIZE} ranks, found {size}"
+            )));
+        }
+        let mut board = Board::new(size as u8);
+        // Highest rank first, like Display draws them
+        for (expected, (rank, tiles)) in (1..=size as u8).rev().zip(rows) {
+            if rank != expected {
+                return Err(ParseBoardError(format!(
+                    "expected rank {expected}, found {rank}"
+                )));
+            }
+            if tiles.len() != size {
+                return Err(ParseBoardError(format!(
+                    "rank {rank} has {} tiles, expected {size}",
+                    tiles.len()
+                )));
             }
+            let start = (rank as usize - 1) * size;
+            board.tiles[start..start + size].copy_from_slice(&tiles);
+        }
+        Ok(board)
+    }
+}
 
-            writeln!(f)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(size: u8) -> Board {
+        let mut board = Board::new(size);
+        board.mark(TileId::new(0, 0), Player::X);
+        board.mark(TileId::new(size - 1, 0), Player::O);
+        board.mark(TileId::new(size / 2, size - 1), Player::X);
+        board
+    }
+
+    #[test]
+    fn compact_round_trips() {
+        for size in 1..=MAX_BOARD_SIZE {
+            let empty = Board::new(size);
+            assert_eq!(Board::from_compact(&empty.to_compact()), Ok(empty));
+            let board = sample(size);
+            assert_eq!(Board::from_compact(&board.to_compact()), Ok(board));
+        }
+    }
+
+    #[test]
+    fn compact_layout() {
+        let mut board = Board::new(3);
+        board.mark(TileId::A1, Player::X);
+        board.mark(TileId::C1, Player::O);
+        board.mark(TileId::C2, Player::X);
+        assert_eq!(board.to_compact(), "X-O--X---");
+    }
+
+    #[test]
+    fn serde_round_trips() {
+        let board = sample(3);
+        let ron = ron::to_string(&board).unwrap();
+        assert_eq!(ron, "\"X-O----X-\"");
+        assert_eq!(ron::from_str::<Board>(&ron).unwrap(), board);
+    }
+
+    #[test]
+    fn compact_rejects_bad_length() {
+        for s in ["", "X-O", "X-O--X--", "X-O--X----", &"-".repeat(16 * 16)] {
+            assert!(Board::from_compact(s).is_err(), "{s:?}");
+        }
+        assert!(ron::from_str::<Board>("\"X-O\"").is_err());
+    }
+
+    #[test]
+    fn compact_rejects_bad_tiles() {
+        for s in ["x-O--X---", "X-O--X--.", "X O--X---", "X-0--X---"] {
+            assert!(Board::from_compact(s).is_err(), "{s:?}");
+        }
+        assert!(ron::from_str::<Board>("\"X-O--X--?\"").is_err());
+    }
+
+    #[test]
+    fn winning_lines_for_none_one_or_several() {
+        let board = Board::from_compact("XO-OX----").unwrap();
+        assert!(board.winning_lines_for(Player::X, 3).is_empty());
+        assert!(board.winning_lines_for(Player::O, 3).is_empty());
+
+        let board = Board::from_compact("XO-OX-O-X").unwrap();
+        assert_eq!(
+            board.winning_lines_for(Player::X, 3),
+            [vec![TileId::A1, TileId::B2, TileId::C3]]
+        );
+        assert!(board.winning_lines_for(Player::O, 3).is_empty());
+
+        // Can't happen in a game, but the validator has to see both
+        let board = Board::from_compact("XXXX--X--").unwrap();
+        let lines = board.winning_lines_for(Player::X, 3);
+        assert_eq!(lines.len(), 2);
+        assert!(lines.contains(&vec![TileId::A1, TileId::B1, TileId::C1]));
+        assert!(lines.contains(&vec![TileId::A1, TileId::A2, TileId::A3]));
+
+        // Shorter lines count on bigger boards
+        let board = Board::from_compact("XX--------------").unwrap();
+        assert_eq!(board.winning_lines_for(Player::X, 2).len(), 1);
+        assert!(board.winning_lines_for(Player::X, 3).is_empty());
+    }
+
+    #[test]
+    fn tile_indices_round_trip() {
+        let board = Board::new(3);
+        for index in 0..=8 {
+            let tile = board.tile_at(index).unwrap();
+            assert_eq!(board.index_of(tile), Some(index));
+        }
+        assert_eq!(board.tile_at(0), Some(TileId::A1));
+        assert_eq!(board.tile_at(2), Some(TileId::C1));
+        assert_eq!(board.tile_at(8), Some(TileId::C3));
+        for index in [9, 10, 255] {
+            assert_eq!(board.tile_at(index), None, "{index}");
+        }
+        assert_eq!(board.index_of(TileId::new(3, 0)), None);
+
+        let board = Board::new(5);
+        assert_eq!(board.tile_at(24), Some(TileId::new(4, 4)));
+        assert_eq!(board.index_of(TileId::new(0, 1)), Some(5));
+        assert_eq!(board.tile_at(25), None);
+    }
+
+    #[test]
+    fn tiles_parse_from_names() {
+        assert_eq!("center".parse(), Ok(TileId::B2));
+        assert_eq!("top-left".parse(), Ok(TileId::A3));
+        assert_eq!("Bottom Right".parse(), Ok(TileId::C1));
+        assert_eq!("b2".parse(), Ok(TileId::B2));
+        assert_eq!("d4".parse(), Ok(TileId::new(3, 3)));
+    }
+
+    #[test]
+    fn unknown_tile_names_are_rejected() {
+        for name in ["centre", "top-middle-left", "a0", "2b", ""] {
+            assert_eq!(
+                name.parse::<TileId>(),
+                Err(ParseTileError(name.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        // Every 3×3 board there is, valid game position or not
+        for n in 0..3u32.pow(9) {
+            let tiles = (0..9)
+                .map(|i| match n / 3u32.pow(i) % 3 {
+                    0 => None,
+                    1 => Some(Player::O),
+                    _ => Some(Player::X),
+                })
+                .collect();
+            let board = Board { size: 3, tiles };
+            assert_eq!(board.to_string().parse(), Ok(board));
+        }
+        for size in 1..=MAX_BOARD_SIZE {
+            let board = sample(size);
+            assert_eq!(board.to_string().parse(), Ok(board));
+        }
+    }
+
+    #[test]
+    fn display_parse_rejects_malformed_boards() {
+        for bad in [
+            "",
+            "3│---\n2│---",
+            "3│---\n2│-?-\n1│---",
+            "3│---\n2│--\n1│---",
+            "3│---\n1│---\n2│---",
+            "3│---\n2│---\nhello",
+        ] {
+            assert!(bad.parse::<Board>().is_err(), "{bad:?}");
         }
-        write!(f, " ╰─────\n   ABC")?;
-        Ok(())
     }
 }
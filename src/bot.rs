@@ -0,0 +1,291 @@
+// The computer opponent. On the standard 3×3 board it plays perfectly, from an
+// opening book and then a full minimax search. Bigger boards are too much to
+// search, there it only looks a move ahead. The server seats it opposite a
+// lone player when bot fill is enabled.
+
+use crate::game::{Board, Conclusion, Game, Player, State, TileId};
+
+// Each symmetry of the board (4 rotations, each optionally mirrored) as a
+// permutation: SYMMETRIES[s][i] is where tile i ends up under symmetry s.
+const SYMMETRIES: [[u8; 9]; 8] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8],
+    [2, 5, 8, 1, 4, 7, 0, 3, 6],
+    [8, 7, 6, 5, 4, 3, 2, 1, 0],
+    [6, 3, 0, 7, 4, 1, 8, 5, 2],
+    [2, 1, 0, 5, 4, 3, 8, 7, 6],
+    [8, 5, 2, 7, 4, 1, 6, 3, 0],
+    [6, 7, 8, 3, 4, 5, 0, 1, 2],
+    [0, 3, 6, 1, 4, 7, 2, 5, 8],
+];
+
+// Optimal replies for the opening, up to symmetry. Keys are the marked tiles,
+// which at this point can only belong to the opponent.
+const OPENING_BOOK: [(&[u8], u8); 4] = [
+    // empty board: take the center
+    (&[], 4),
+    // corner opening: take the center
+    (&[0], 4),
+    // edge opening: take the center
+    (&[1], 4),
+    // center opening: take a corner
+    (&[4], 0),
+];
+
+// Scores positions so a bot can pick between moves. Higher is better for
+// `for_player`.
+pub trait Evaluator {
+    fn evaluate(&self, game: &Game, for_player: Player) -> i32;
+
+    // The legal move leading to the best scoring position for the player on turn
+    fn choose_move(&self, game: &Game) -> Option<TileId> {
+        let State::Playing(me) = game.state else {
+            return None;
+        };
+        legal_moves(game).max_by_key(|&tile| self.evaluate(&play(game, tile), me))
+    }
+}
+
+// Searches the whole game tree, so it never loses
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Minimax;
+
+impl Evaluator for Minimax {
+    fn evaluate(&self, game: &Game, for_player: Player) -> i32 {
+        minimax(game, for_player, 0)
+    }
+}
+
+// Looks a single move ahead, taking a win when there is one and blocking the
+// opponent's otherwise. Nowhere near perfect, but cheap on any board size.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Greedy;
+
+impl Evaluator for Greedy {
+    fn evaluate(&self, game: &Game, for_player: Player) -> i32 {
+        match game.state {
+            State::Concluded(Conclusion::Win(winner)) if winner == for_player => 1,
+            State::Concluded(Conclusion::Win(_)) => -1,
+            State::Concluded(Conclusion::Draw) => 0,
+            State::Playing(turn) if turn == for_player => 0,
+            State::Playing(_) => {
+                let opponent_wins = legal_moves(game).any(|tile| {
+                    matches!(play(game, tile).state, State::Concluded(Conclusion::Win(winner)) if winner != for_player)
+                });
+                if opponent_wins {
+                    -1
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+// Only boards up to this size are small enough to search all the way through
+const MAX_SOLVED_SIZE: u8 = 3;
+
+pub fn best_move(game: &Game) -> Option<TileId> {
+    if game.board.size() > MAX_SOLVED_SIZE {
+        return Greedy.choose_move(game);
+    }
+    book_move(&game.board).or_else(|| Minimax.choose_move(game))
+}
+
+// The outcome of the game if both sides play perfectly from here on, None if
+// the board is too big to work that out
+pub fn solve(game: &Game) -> Option<Conclusion> {
+    match game.state {
+        State::Concluded(conclusion) => Some(conclusion),
+        State::Playing(_) if game.board.size() > MAX_SOLVED_SIZE => None,
+        State::Playing(turn) => Some(match Minimax.evaluate(game, turn) {
+            0 => Conclusion::Draw,
+            score if score > 0 => Conclusion::Win(turn),
+            _ => Conclusion::Win(!turn),
+        }),
+    }
+}
+
+// Only knows the 3×3 board
+fn book_move(board: &Board) -> Option<TileId> {
+    if board.size() != 3 || board.mark_count() > 1 {
+        return None;
+    }
+
+    let marked: Vec<u8> = board
+        .into_iter()
+        .filter(|(_, mark)| mark.is_some())
+        .filter_map(|(tile, _)| board.index_of(tile))
+        .collect();
+
+    for sym in &SYMMETRIES {
+        for (position, reply) in OPENING_BOOK {
+            let matches = position.len() == marked.len()
+                && marked.iter().all(|&i| position.contains(&sym[i as usize]));
+            if matches {
+                // Map the reply back from the canonical position to the real board
+                let tile = sym.iter().position(|&i| i == reply).unwrap() as u8;
+                return board.tile_at(tile);
+            }
+        }
+    }
+
+    None
+}
+
+fn minimax(game: &Game, me: Player, depth: i32) -> i32 {
+    match game.state {
+        State::Concluded(Conclusion::Win(winner)) if winner == me => 10 - depth,
+        State::Concluded(Conclusion::Win(_)) => depth - 10,
+        State::Concluded(Conclusion::Draw) => 0,
+        State::Playing(turn) => {
+            let scores = legal_moves(game).map(|tile| minimax(&play(game, tile), me, depth + 1));
+            if turn == me {
+                scores.max()
+            } else {
+                scores.min()
+            }
+            .unwrap_or(0)
+        }
+    }
+}
+
+fn play(game: &Game, tile: TileId) -> Game {
+    let mut next = game.clone();
+    if let State::Playing(turn) = game.state {
+        let _ = next.play(tile, turn);
+    }
+    next
+}
+
+fn legal_moves(game: &Game) -> impl Iterator<Item = TileId> {
+    match game.state {
+        State::Playing(_) => game.available_moves(),
+        State::Concluded(_) => Vec::new(),
+    }
+    .into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every position the book has an answer for: the empty board and each
+    // single opening mark, with either side moving first
+    fn book_positions() -> Vec<Game> {
+        let mut positions = Vec::new();
+        for first in [Player::O, Player::X] {
+            let game = Game::new(first);
+            for tile in game.available_moves() {
+                positions.push(play(&game, tile));
+            }
+            positions.push(game);
+        }
+        positions
+    }
+
+    #[test]
+    fn book_covers_every_opening() {
+        for game in book_positions() {
+            assert!(
+                book_move(&game.board).is_some(),
+                "no book move for\n{}",
+                game.board
+            );
+        }
+    }
+
+    #[test]
+    fn book_agrees_with_minimax() {
+        for game in book_positions() {
+            let State::Playing(me) = game.state else {
+                unreachable!("openings are still being played");
+            };
+            let best = legal_moves(&game)
+                .map(|tile| Minimax.evaluate(&play(&game, tile), me))
+                .max()
+                .unwrap();
+            let tile = book_move(&game.board).unwrap();
+            assert_eq!(
+                Minimax.evaluate(&play(&game, tile), me),
+                best,
+                "book plays {tile} on\n{}",
+                game.board
+            );
+        }
+    }
+
+    #[test]
+    fn book_stays_out_of_later_positions() {
+        let game = play(&play(&Game::new(Player::O), TileId::A1), TileId::B2);
+        assert_eq!(book_move(&game.board), None);
+        assert_eq!(book_move(&Game::with_rules(4, 3, Player::O).board), None);
+    }
+
+    #[test]
+    fn perfect_play_draws() {
+        let mut game = Game::new(Player::O);
+        while let Some(tile) = best_move(&game) {
+            game = play(&game, tile);
+        }
+        assert!(matches!(game.state, State::Concluded(Conclusion::Draw)));
+    }
+
+    // Only cares about holding the center
+    struct AlwaysCenter;
+
+    impl Evaluator for AlwaysCenter {
+        fn evaluate(&self, game: &Game, for_player: Player) -> i32 {
+            (game.board[TileId::B2] == Some(for_player)).into()
+        }
+    }
+
+    #[test]
+    fn evaluator_picks_its_favourite_move() {
+        for first in [Player::O, Player::X] {
+            let game = Game::new(first);
+            assert_eq!(AlwaysCenter.choose_move(&game), Some(TileId::B2));
+        }
+
+        // With the center gone every move scores the same, any legal one will do
+        let game = play(&Game::new(Player::O), TileId::B2);
+        let tile = AlwaysCenter.choose_move(&game).unwrap();
+        assert!(game.can_play(tile, Player::X).is_ok());
+
+        let mut over = Game::new(Player::O);
+        over.forfeit(Player::O);
+        assert_eq!(AlwaysCenter.choose_move(&over), None);
+    }
+
+    fn replay(first: Player, tiles: &[TileId]) -> Game {
+        tiles
+            .iter()
+            .fold(Game::new(first), |game, &tile| play(&game, tile))
+    }
+
+    #[test]
+    fn takes_a_win_when_there_is_one() {
+        // O can win on A3 or block X on B3, winning right away beats both
+        let game = replay(Player::O, &[TileId::A1, TileId::B1, TileId::A2, TileId::B2]);
+        assert_eq!(best_move(&game), Some(TileId::A3));
+        assert_eq!(solve(&game), Some(Conclusion::Win(Player::O)));
+    }
+
+    #[test]
+    fn blocks_a_line_about_to_be_completed() {
+        let game = replay(Player::O, &[TileId::A1, TileId::B2, TileId::A2]);
+        assert_eq!(best_move(&game), Some(TileId::A3));
+        assert_eq!(solve(&game), Some(Conclusion::Draw));
+    }
+
+    #[test]
+    fn holds_the_draw_from_any_opening() {
+        // Whatever the first mark, perfect play from then on can't lose it
+        for tile in Game::new(Player::O).available_moves() {
+            let mut game = replay(Player::O, &[tile]);
+            while let Some(tile) = best_move(&game) {
+                game = play(&game, tile);
+            }
+            assert!(matches!(game.state, State::Concluded(Conclusion::Draw)));
+        }
+    }
+}
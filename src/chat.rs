@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+
+// Recent chat messages, bounded both by count and by total bytes so a few huge
+// messages can't blow the budget. The oldest messages are evicted first.
+#[derive(Debug)]
+pub struct ChatHistory {
+    messages: VecDeque<(String, String)>,
+    bytes: usize,
+    max_messages: usize,
+    max_bytes: usize,
+    evicted: u64,
+}
+
+impl ChatHistory {
+    pub fn new(max_messages: usize, max_bytes: usize) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(max_messages),
+            bytes: 0,
+            max_messages,
+            max_bytes,
+            evicted: 0,
+        }
+    }
+
+    pub fn push(&mut self, from: String, msg: String) {
+        self.bytes += from.len() + msg.len();
+        self.messages.push_back((from, msg));
+
+        while self.messages.len() > self.max_messages || self.bytes > self.max_bytes {
+            let Some((from, msg)) = self.messages.pop_front() else {
+                break;
+            };
+            self.bytes -= from.len() + msg.len();
+            self.evicted += 1;
+        }
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (&str, &str)> {
+        self.messages
+            .iter()
+            .map(|(from, msg)| (from.as_str(), msg.as_str()))
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Total number of messages evicted to stay within budget
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+impl Default for ChatHistory {
+    fn default() -> Self {
+        Self::new(64, 16 * 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest_over_byte_budget() {
+        let mut history = ChatHistory::new(10, 20);
+        history.push("a".to_string(), "123456789".to_string());
+        history.push("b".to_string(), "123456789".to_string());
+        assert_eq!(history.bytes(), 20);
+        assert_eq!(history.evicted(), 0);
+
+        history.push("c".to_string(), "12345".to_string());
+        assert_eq!(history.evicted(), 1);
+        assert_eq!(history.bytes(), 16);
+        let from: Vec<_> = history.iter().map(|(from, _)| from).collect();
+        assert_eq!(from, ["b", "c"]);
+    }
+
+    #[test]
+    fn oversized_message_evicts_everything() {
+        let mut history = ChatHistory::new(10, 20);
+        history.push("a".to_string(), "hi".to_string());
+        history.push("b".to_string(), "x".repeat(100));
+        assert_eq!(history.evicted(), 2);
+        assert_eq!(history.bytes(), 0);
+        assert_eq!(history.iter().count(), 0);
+    }
+
+    #[test]
+    fn evicts_over_message_count() {
+        let mut history = ChatHistory::new(2, 1024);
+        for from in ["a", "b", "c"] {
+            history.push(from.to_string(), "hi".to_string());
+        }
+        assert_eq!(history.evicted(), 1);
+        assert_eq!(history.iter().count(), 2);
+    }
+}
@@ -1,73 +1,416 @@
 use std::net::SocketAddr;
 
-use bytes::BytesMut;
-use serde::de::DeserializeOwned;
+use bytes::{Buf, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufReader},
     net::TcpStream,
 };
 
-use crate::message::{Error, Message};
+use crate::{
+    log::{error, info, warn},
+    message::{Error, Message, Notification, Request},
+    text,
+};
 
 pub type ConnectionId = u32;
 
+// Set to log the raw bytes of every message sent and received, at info level
+// so it shows without also turning on RUST_LOG=debug
+const LOG_WIRE_ENV: &str = "TTT_LOG_WIRE";
+// Logged messages are truncated to this many bytes
+const LOG_WIRE_LIMIT: usize = 512;
+
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// How messages are delimited on the wire
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    // One RON message per line, what clients have always spoken
+    #[default]
+    Lines,
+    // Each message is preceded by its length in bytes, as a big-endian u32
+    LengthPrefixed,
+    // Commands typed by a person, one per line, answered in plain text. See
+    // the text module.
+    Text,
+}
+
 #[derive(Debug)]
 pub struct Connection {
     stream: BufReader<TcpStream>,
     buffer: BytesMut,
+    framing: Framing,
+    log_wire: bool,
+    // A peer sending more than this without finishing a message is dropped
+    pub max_message_size: usize,
     pub addr: SocketAddr,
 }
 
 impl Connection {
     pub fn new(socket: TcpStream, addr: SocketAddr) -> Self {
+        Self::with_framing(socket, addr, Framing::default())
+    }
+
+    pub fn with_framing(socket: TcpStream, addr: SocketAddr, framing: Framing) -> Self {
         Self {
             buffer: BytesMut::with_capacity(256),
             stream: BufReader::new(socket),
+            framing,
+            log_wire: std::env::var_os(LOG_WIRE_ENV).is_some(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
             addr,
         }
     }
 
-    pub async fn recv<'a, T>(&mut self) -> anyhow::Result<Option<T>>
+    pub async fn recv<T>(&mut self) -> anyhow::Result<Option<T>>
     where
         T: DeserializeOwned,
     {
         use ron::de::from_bytes;
         use ron::error::SpannedError;
-        use ron::Error::{Eof, ExpectedDifferentLength};
 
-        self.buffer.clear();
+        match self.framing {
+            Framing::LengthPrefixed => return self.recv_framed().await,
+            Framing::Text => return self.recv_text().await,
+            Framing::Lines => {}
+        }
+
+        // Whatever is left in the buffer belongs to the next message, a peer can
+        // send several before we get around to reading them
         loop {
-            match from_bytes::<T>(&self.buffer) {
-                Ok(mes) => return Ok(Some(mes)),
-                Err(SpannedError { code, .. }) => match code {
-                    ExpectedDifferentLength { .. } => {}
-                    Eof => {}
-                    e => {
-                        println!("error reading message {:#?}", e);
-                        self.send(Error::InvalidMessage(format!("{}", e)))
-                            .await
-                            .unwrap();
-                        self.buffer.clear();
+            // One message per line, though a single message without the newline
+            // is accepted too
+            let line_end = self.buffer.iter().position(|&b| b == b'\n').map(|i| i + 1);
+            let frame_len = line_end.unwrap_or(self.buffer.len());
+            let frame = &self.buffer[..frame_len];
+
+            if !frame.iter().all(u8::is_ascii_whitespace) {
+                match from_bytes::<T>(frame) {
+                    Ok(mes) => {
+                        self.log_wire("<-", frame);
+                        self.buffer.advance(frame_len);
+                        return Ok(Some(mes));
+                    }
+                    // Without a newline it may just be incomplete, wait for the rest
+                    Err(_) if line_end.is_none() => {}
+                    Err(SpannedError { code: e, .. }) => {
+                        warn!("{} sent an invalid message: {}", self.addr, e);
+                        self.buffer.advance(frame_len);
+                        self.send(Error::InvalidMessage(format!("{}", e))).await?;
+                        continue;
                     }
-                },
+                }
+            } else if line_end.is_some() {
+                self.buffer.advance(frame_len);
+                continue;
+            }
+
+            if !self.fill_buffer().await? {
+                return Ok(None);
             }
+        }
+    }
 
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
-                if self.buffer.is_empty() {
+    async fn recv_framed<T>(&mut self) -> anyhow::Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        loop {
+            if let Some(header) = self.buffer.get(..4) {
+                let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+                if len > self.max_message_size {
+                    return Err(self.reject_oversized().await);
+                }
+                if self.buffer.len() >= 4 + len {
+                    self.buffer.advance(4);
+                    let body = self.buffer.split_to(len);
+                    match ron::de::from_bytes::<T>(&body) {
+                        Ok(mes) => {
+                            self.log_wire("<-", &body);
+                            return Ok(Some(mes));
+                        }
+                        Err(e) => {
+                            warn!("{} sent an invalid message: {}", self.addr, e.code);
+                            self.send(Error::InvalidMessage(format!("{}", e.code)))
+                                .await?;
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            if !self.fill_buffer().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    async fn recv_text<T>(&mut self) -> anyhow::Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        loop {
+            let Some(line_end) = self.buffer.iter().position(|&b| b == b'\n') else {
+                if !self.fill_buffer().await? {
                     return Ok(None);
                 }
-                anyhow::bail!("Connection reset by peer");
+                continue;
+            };
+            let line = self.buffer.split_to(line_end + 1);
+            self.log_wire("<-", &line);
+            let line = String::from_utf8_lossy(&line);
+            if line.trim().is_empty() {
+                continue;
             }
+            match text::parse_command(&line) {
+                // Whoever is reading expects RON, hand them the command in it
+                Ok(req) => return Ok(Some(ron::from_str(&ron::to_string(&req)?)?)),
+                Err(reason) => self.send(Error::InvalidMessage(reason)).await?,
+            }
+        }
+    }
+
+    // Reads whatever is available into the buffer, false once the peer is done
+    // sending and every message it sent has been read
+    async fn fill_buffer(&mut self) -> anyhow::Result<bool> {
+        // Complete messages are taken out before reading more, so anything
+        // this big is a single message that's too large
+        if self.buffer.len() > self.max_message_size {
+            return Err(self.reject_oversized().await);
+        }
+        if 0 == self.stream.read_buf(&mut self.buffer).await? {
+            if self.buffer.is_empty() {
+                return Ok(false);
+            }
+            anyhow::bail!("Connection reset by peer");
         }
+        Ok(true)
+    }
+
+    // Tells the peer why it's being dropped, the returned error is for us
+    async fn reject_oversized(&mut self) -> anyhow::Error {
+        let reason = format!("message is larger than {} bytes", self.max_message_size);
+        self.buffer.clear();
+        let _ = self.send(Error::InvalidMessage(reason.clone())).await;
+        anyhow::anyhow!(reason)
     }
 
     pub async fn send(&mut self, mes: impl Into<Message>) -> tokio::io::Result<()> {
-        self.stream
-            .write_all(
-                format!("{}\n", ron::ser::to_string::<Message>(&mes.into()).unwrap()).as_bytes(),
-            )
-            .await?;
-        self.stream.flush().await?;
-        Ok(())
+        let mes = mes.into();
+        if self.framing == Framing::Text {
+            return match text::describe(&mes) {
+                Some(text) => self.send_line(text).await,
+                None => Ok(()),
+            };
+        }
+        self.write(&mes).await
+    }
+
+    // Requests go over the wire bare rather than wrapped in a Message, this is
+    // the client side counterpart to `recv::<Request>`
+    pub async fn request(&mut self, req: Request) -> tokio::io::Result<()> {
+        self.write(&req).await
+    }
+
+    async fn write<T: Serialize + std::fmt::Debug>(&mut self, value: &T) -> tokio::io::Result<()> {
+        let ron = match ron::ser::to_string(value) {
+            Ok(ron) => ron,
+            Err(e) => {
+                // A bug on our end, better to skip the message than take the connection down
+                error!("failed to serialize {:?}: {}", value, e);
+                return Ok(());
+            }
+        };
+        match self.framing {
+            // Only messages are rendered as text, anything else goes out as is
+            Framing::Lines | Framing::Text => self.send_line(ron).await,
+            Framing::LengthPrefixed => self.send_framed(ron).await,
+        }
+    }
+
+    async fn send_line(&mut self, ron: String) -> tokio::io::Result<()> {
+        let line = format!("{ron}\n");
+        self.log_wire("->", line.as_bytes());
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.flush().await
+    }
+
+    async fn send_framed(&mut self, ron: String) -> tokio::io::Result<()> {
+        let Ok(len) = u32::try_from(ron.len()) else {
+            error!("message too big to send, {} bytes", ron.len());
+            return Ok(());
+        };
+        self.log_wire("->", ron.as_bytes());
+        self.stream.write_all(&len.to_be_bytes()).await?;
+        self.stream.write_all(ron.as_bytes()).await?;
+        self.stream.flush().await
+    }
+
+    // Says goodbye and shuts down our half of the connection, so the peer sees a
+    // clean EOF rather than a reset
+    pub async fn close(&mut self, reason: Option<String>) -> tokio::io::Result<()> {
+        let goodbye = reason.unwrap_or_else(|| "goodbye".to_string());
+        self.send(Notification::ServerInfo(goodbye)).await?;
+        self.stream.shutdown().await
+    }
+
+    fn log_wire(&self, direction: &str, bytes: &[u8]) {
+        if !self.log_wire {
+            return;
+        }
+        let shown = &bytes[..bytes.len().min(LOG_WIRE_LIMIT)];
+        info!(
+            "{} {} {} bytes: {}",
+            self.addr,
+            direction,
+            bytes.len(),
+            String::from_utf8_lossy(shown).trim_end()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{net::TcpListener, time::timeout};
+
+    use super::*;
+
+    // Both ends of a local connection, ours first
+    async fn pair(framing: Framing) -> (Connection, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, peer) = listener.accept().await.unwrap();
+        (
+            Connection::with_framing(server, peer, framing),
+            Connection::with_framing(client, addr, framing),
+        )
+    }
+
+    async fn recv<T: DeserializeOwned>(con: &mut Connection) -> Option<T> {
+        timeout(Duration::from_secs(5), con.recv())
+            .await
+            .expect("nothing arrived")
+            .unwrap()
+    }
+
+    #[derive(Debug)]
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("can't serialize this"))
+        }
+    }
+
+    #[tokio::test]
+    async fn unserializable_messages_are_skipped() {
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let (mut ours, mut theirs) = pair(framing).await;
+            ours.write(&Unserializable).await.unwrap();
+            ours.send(Notification::Paused(true)).await.unwrap();
+
+            assert!(matches!(
+                recv(&mut theirs).await,
+                Some(Message::Notification(Notification::Paused(true)))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn close_says_goodbye_before_eof() {
+        let (mut ours, mut theirs) = pair(Framing::Lines).await;
+        ours.close(Some("server shutting down".to_string()))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            recv(&mut theirs).await,
+            Some(Message::Notification(Notification::ServerInfo(msg))) if msg == "server shutting down"
+        ));
+        assert!(recv::<Message>(&mut theirs).await.is_none());
+
+        // Only our half is shut, we can still hear from them
+        theirs.request(Request::GetVersion).await.unwrap();
+        assert!(matches!(recv(&mut ours).await, Some(Request::GetVersion)));
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_drop_the_connection() {
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let (mut ours, mut theirs) = pair(framing).await;
+            ours.max_message_size = 1024;
+
+            // Never a complete message, however much of it is read
+            let garbage = match framing {
+                Framing::LengthPrefixed => 1_000_000u32.to_be_bytes().to_vec(),
+                _ => Vec::new(),
+            };
+            let garbage = [garbage, vec![b'('; 8 * 1024]].concat();
+            theirs.stream.get_mut().write_all(&garbage).await.unwrap();
+
+            let received = timeout(Duration::from_secs(5), ours.recv::<Request>())
+                .await
+                .expect("still waiting for the rest of the message");
+            assert!(received.is_err());
+            assert!(ours.buffer.is_empty());
+            assert!(matches!(
+                recv(&mut theirs).await,
+                Some(Message::Response(Err(Error::InvalidMessage(_))))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip_over_each_framing() {
+        use crate::board::{Board, Player, TileId};
+
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let (mut ours, mut theirs) = pair(framing).await;
+            let mut board = Board::new(3);
+            board.mark(TileId::B2, Player::X);
+
+            // All sent before anything is read, so they arrive back to back
+            theirs
+                .request(Request::Chat("two\nlines (and «more»)".to_string()))
+                .await
+                .unwrap();
+            theirs
+                .request(Request::MarkAt { row: 2, col: 0 })
+                .await
+                .unwrap();
+            ours.send(Notification::TurnPlayed {
+                board: board.clone(),
+                next: Some(Player::O),
+            })
+            .await
+            .unwrap();
+            ours.send(Message::Response(Err(Error::InvalidParam(
+                "bad\nparam".to_string(),
+            ))))
+            .await
+            .unwrap();
+
+            assert!(matches!(
+                recv(&mut ours).await,
+                Some(Request::Chat(msg)) if msg == "two\nlines (and «more»)"
+            ));
+            assert!(matches!(
+                recv(&mut ours).await,
+                Some(Request::MarkAt { row: 2, col: 0 })
+            ));
+            assert!(matches!(
+                recv(&mut theirs).await,
+                Some(Message::Notification(Notification::TurnPlayed { board: b, next: Some(Player::O) }))
+                    if b == board
+            ));
+            assert!(matches!(
+                recv(&mut theirs).await,
+                Some(Message::Response(Err(Error::InvalidParam(reason)))) if reason == "bad\nparam"
+            ));
+        }
     }
 }
@@ -1,6 +1,11 @@
+#[cfg(feature = "debug-snapshots")]
+use std::collections::VecDeque;
+
+use std::fmt::Display;
+
 use serde::{Deserialize, Serialize};
 
-pub use crate::board::{Board, Player, TileId};
+pub use crate::board::{Board, Player, TileId, MAX_BOARD_SIZE};
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum Conclusion {
@@ -8,6 +13,27 @@ pub enum Conclusion {
     Draw,
 }
 
+impl Display for Conclusion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Conclusion::Win(player) => write!(f, "{player} wins!"),
+            Conclusion::Draw => write!(f, "Draw"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, thiserror::Error)]
+pub enum MoveError {
+    #[error("the game is over")]
+    Concluded(Conclusion),
+    #[error("it's {turn}'s turn")]
+    NotYourTurn { turn: Player },
+    #[error("{0} is not on the board")]
+    OutOfBounds(TileId),
+    #[error("that tile is already marked by {by}")]
+    Occupied { tile: TileId, by: Player },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum State {
     Playing(Player),
@@ -20,20 +46,90 @@ impl Default for State {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+impl Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            State::Playing(player) => write!(f, "{player} to move"),
+            State::Concluded(_) => write!(f, "game over"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Game {
     pub board: Board,
     pub state: State,
+    // How many marks in a row it takes to win
+    #[serde(default = "default_win_len")]
+    pub win_len: u8,
+    // Every mark made so far, in order
+    #[serde(default)]
+    pub moves: Vec<(Player, TileId)>,
+    #[cfg(feature = "debug-snapshots")]
+    #[serde(skip)]
+    snapshots: VecDeque<Game>,
+}
+
+#[cfg(feature = "debug-snapshots")]
+const MAX_SNAPSHOTS: usize = 16;
+
+fn default_win_len() -> u8 {
+    3
+}
+
+impl Default for Game {
+    fn default() -> Self {
+        Game::new(Player::default())
+    }
 }
 
 impl Game {
     pub fn new(first_turn: Player) -> Self {
+        Game::with_rules(3, default_win_len(), first_turn)
+    }
+
+    // A `size`×`size` board where `win_len` in a row wins
+    pub fn with_rules(size: u8, win_len: u8, first_turn: Player) -> Self {
+        assert!(
+            (1..=size).contains(&win_len),
+            "win length must be between 1 and the board size"
+        );
         Game {
-            board: Board::default(),
+            board: Board::new(size),
             state: State::Playing(first_turn),
+            win_len,
+            moves: Vec::new(),
+            #[cfg(feature = "debug-snapshots")]
+            snapshots: VecDeque::new(),
         }
     }
 
+    // Whether `by` is allowed to mark `tile` right now
+    pub fn can_play(&self, tile: TileId, by: Player) -> Result<(), MoveError> {
+        match self.state {
+            State::Concluded(conclusion) => Err(MoveError::Concluded(conclusion)),
+            State::Playing(turn) if turn != by => Err(MoveError::NotYourTurn { turn }),
+            State::Playing(_) if !self.board.contains(tile) => Err(MoveError::OutOfBounds(tile)),
+            State::Playing(_) => match self.board[tile] {
+                Some(marked_by) => Err(MoveError::Occupied {
+                    tile,
+                    by: marked_by,
+                }),
+                None => Ok(()),
+            },
+        }
+    }
+
+    // Marks `tile` for `by` and moves the game on, to the next turn or to its
+    // conclusion, in one step. Nothing changes if the move isn't allowed.
+    pub fn play(&mut self, tile: TileId, by: Player) -> Result<(), MoveError> {
+        self.can_play(tile, by)?;
+        self.board.mark(tile, by);
+        self.moves.push((by, tile));
+        self.next_turn();
+        Ok(())
+    }
+
     pub fn try_turn(&mut self, tile: TileId) -> bool {
         if !self.is_valid_mark(tile) {
             return false;
@@ -43,6 +139,7 @@ impl Game {
             State::Concluded(_) => false,
             State::Playing(turn) => {
                 self.board.mark(tile, turn);
+                self.moves.push((turn, tile));
                 true
             }
         }
@@ -50,53 +147,85 @@ impl Game {
 
     pub fn has_game_concluded(&self) -> Option<Conclusion> {
         let mark_count = self.board.mark_count();
-        if mark_count < 3 {
+        if mark_count < self.win_len {
             return None;
         }
 
-        let a1 = self.board[TileId::A1];
-        let a2 = self.board[TileId::A2];
-        let a3 = self.board[TileId::A3];
-        let b1 = self.board[TileId::B1];
-        let b2 = self.board[TileId::B2];
-        let b3 = self.board[TileId::B3];
-        let c1 = self.board[TileId::C1];
-        let c2 = self.board[TileId::C2];
-        let c3 = self.board[TileId::C3];
-
-        if let Some(player) = a1 {
-            if (a1 == a3 && a1 == a2) || (a1 == c1 && a1 == b1) || (a1 == c3 && a1 == b2) {
-                return Some(Conclusion::Win(player));
-            }
+        let winner = self.board.lines(self.win_len).into_iter().find_map(|line| {
+            let player = self.board[line[0]]?;
+            line.iter()
+                .all(|&tile| self.board[tile] == Some(player))
+                .then_some(player)
+        });
+        if let Some(player) = winner {
+            return Some(Conclusion::Win(player));
         }
 
-        if let Some(player) = c1 {
-            if (c1 == c3 && c1 == c2) || (c1 == a3 && c1 == b2) {
-                return Some(Conclusion::Win(player));
-            }
+        if self.board.is_full() {
+            return Some(Conclusion::Draw);
         }
 
-        if let Some(player) = b2 {
-            if (b2 == a2 && b2 == c2) || (b2 == b1 && b2 == b3) {
-                return Some(Conclusion::Win(player));
-            }
-        }
+        None
+    }
 
-        if let Some(player) = a3 {
-            if a3 == c3 && c3 == b3 {
-                return Some(Conclusion::Win(player));
-            }
+    pub fn legal_move_count(&self) -> u8 {
+        match self.state {
+            State::Concluded(_) => 0,
+            State::Playing(_) => self.board.tile_count() - self.board.mark_count(),
         }
+    }
 
-        if mark_count == 9 {
-            return Some(Conclusion::Draw);
+    // Every tile nobody has marked yet, whether or not the game is still going
+    pub fn available_moves(&self) -> Vec<TileId> {
+        self.board
+            .into_iter()
+            .filter(|(_, mark)| mark.is_none())
+            .map(|(tile, _)| tile)
+            .collect()
+    }
+
+    // A draw by filling the board, as opposed to one that was agreed or adjudicated
+    pub fn is_cats_game(&self) -> bool {
+        self.board.is_full()
+            && self.winning_lines_for(Player::O).is_empty()
+            && self.winning_lines_for(Player::X).is_empty()
+    }
+
+    pub fn winning_lines_for(&self, player: Player) -> Vec<Vec<TileId>> {
+        self.board.winning_lines_for(player, self.win_len)
+    }
+
+    // Lines the player could still complete, i.e. the ones the opponent hasn't
+    // marked anywhere. Capped at 255, which only tiny win lengths on big boards
+    // come close to.
+    pub fn open_lines_for(&self, player: Player) -> u8 {
+        let open = self
+            .board
+            .lines(self.win_len)
+            .iter()
+            .filter(|line| line.iter().all(|&tile| self.board[tile] != Some(!player)))
+            .count();
+        open.min(u8::MAX as usize) as u8
+    }
+
+    // Ends the game early, if it's still going
+    pub fn conclude(&mut self, conclusion: Conclusion) -> Option<Conclusion> {
+        match self.state {
+            State::Concluded(_) => None,
+            State::Playing(_) => {
+                self.state = State::Concluded(conclusion);
+                Some(conclusion)
+            }
         }
+    }
 
-        None
+    // Concludes the game as a win for the other player, if it's still going
+    pub fn forfeit(&mut self, player: Player) -> Option<Conclusion> {
+        self.conclude(Conclusion::Win(!player))
     }
 
     fn is_valid_mark(&self, tile: TileId) -> bool {
-        self.board[tile].is_none()
+        self.board.contains(tile) && self.board[tile].is_none()
     }
 
     pub fn next_turn(&mut self) {
@@ -106,6 +235,321 @@ impl Game {
             } else {
                 self.state = State::Playing(!player)
             };
+
+            #[cfg(feature = "debug-snapshots")]
+            self.take_snapshot();
+        }
+    }
+
+    // The board as it was after the first `n` moves, None if fewer than `n`
+    // moves have been made
+    pub fn board_at(&self, n: usize) -> Option<Board> {
+        let mut board = Board::new(self.board.size());
+        for &(player, tile) in self.moves.get(..n)? {
+            board.mark(tile, player);
         }
+        Some(board)
+    }
+
+    // The game as it was after the nth move (0-based) of the retained history
+    #[cfg(feature = "debug-snapshots")]
+    pub fn snapshot_at(&self, n: usize) -> Option<&Game> {
+        self.snapshots.get(n)
+    }
+
+    #[cfg(feature = "debug-snapshots")]
+    fn take_snapshot(&mut self) {
+        if self.snapshots.len() == MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Game {
+            board: self.board.clone(),
+            state: self.state.clone(),
+            win_len: self.win_len,
+            moves: self.moves.clone(),
+            snapshots: VecDeque::new(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINES: [[TileId; 3]; 8] = [
+        [TileId::A1, TileId::B1, TileId::C1],
+        [TileId::A2, TileId::B2, TileId::C2],
+        [TileId::A3, TileId::B3, TileId::C3],
+        [TileId::A1, TileId::A2, TileId::A3],
+        [TileId::B1, TileId::B2, TileId::B3],
+        [TileId::C1, TileId::C2, TileId::C3],
+        [TileId::A1, TileId::B2, TileId::C3],
+        [TileId::A3, TileId::B2, TileId::C1],
+    ];
+
+    fn game_with(marks: &[(TileId, Player)]) -> Game {
+        let mut game = Game::default();
+        for &(tile, player) in marks {
+            game.board.mark(tile, player);
+        }
+        game
+    }
+
+    #[test]
+    fn every_line_wins() {
+        for line in LINES {
+            for player in [Player::O, Player::X] {
+                let game = game_with(&line.map(|tile| (tile, player)));
+                assert_eq!(
+                    game.has_game_concluded(),
+                    Some(Conclusion::Win(player)),
+                    "{line:?} for {player}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mixed_line_doesnt_win() {
+        for line in LINES {
+            let game = game_with(&[
+                (line[0], Player::O),
+                (line[1], Player::O),
+                (line[2], Player::X),
+            ]);
+            assert_eq!(game.has_game_concluded(), None, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn full_board_without_a_line_is_a_draw() {
+        // O X O
+        // O X X
+        // X O O
+        use Player::{O, X};
+        let game = game_with(&[
+            (TileId::A1, X),
+            (TileId::B1, O),
+            (TileId::C1, O),
+            (TileId::A2, O),
+            (TileId::B2, X),
+            (TileId::C2, X),
+            (TileId::A3, O),
+            (TileId::B3, X),
+            (TileId::C3, O),
+        ]);
+        assert_eq!(game.has_game_concluded(), Some(Conclusion::Draw));
+    }
+
+    #[test]
+    fn too_few_marks_to_conclude() {
+        let game = game_with(&[(TileId::A1, Player::O), (TileId::B1, Player::O)]);
+        assert_eq!(game.has_game_concluded(), None);
+    }
+
+    #[test]
+    fn legal_moves_count_down_to_the_end() {
+        use Player::{O, X};
+        let mut game = Game::new(O);
+        assert_eq!(game.legal_move_count(), 9);
+
+        let moves = [TileId::A1, TileId::B1, TileId::A2, TileId::B2];
+        for (n, (tile, player)) in moves.into_iter().zip([O, X, O, X]).enumerate() {
+            game.play(tile, player).unwrap();
+            assert_eq!(game.legal_move_count(), 8 - n as u8);
+        }
+
+        // Tiles are still free, but the game is over
+        game.play(TileId::A3, O).unwrap();
+        assert!(matches!(game.state, State::Concluded(Conclusion::Win(O))));
+        assert_eq!(game.legal_move_count(), 0);
+        assert_eq!(game.available_moves().len(), 4);
+    }
+
+    #[cfg(feature = "debug-snapshots")]
+    #[test]
+    fn snapshots_match_the_replayed_game() {
+        let mut game = Game::new(Player::O);
+        let moves = [TileId::B2, TileId::A1, TileId::C3, TileId::A3];
+        for (tile, player) in moves
+            .into_iter()
+            .zip([Player::O, Player::X].into_iter().cycle())
+        {
+            game.play(tile, player).unwrap();
+        }
+
+        for n in 0..moves.len() {
+            let snapshot = game.snapshot_at(n).unwrap();
+            assert_eq!(Some(snapshot.board.clone()), game.board_at(n + 1));
+            assert_eq!(snapshot.moves, game.moves[..=n]);
+        }
+        assert!(game.snapshot_at(moves.len()).is_none());
+    }
+
+    #[cfg(feature = "debug-snapshots")]
+    #[test]
+    fn only_the_latest_snapshots_are_kept() {
+        // Filled in order on a 5×5 board the marks make a checkerboard, no five
+        // in a row before the 21st move
+        let mut game = Game::with_rules(5, 5, Player::O);
+        let moves = MAX_SNAPSHOTS + 2;
+        for index in 0..moves as u8 {
+            let tile = game.board.tile_at(index).unwrap();
+            let player = if index % 2 == 0 { Player::O } else { Player::X };
+            game.play(tile, player).unwrap();
+        }
+
+        assert!(game.snapshot_at(MAX_SNAPSHOTS).is_none());
+        let oldest = game.snapshot_at(0).unwrap();
+        assert_eq!(oldest.moves.len(), moves - MAX_SNAPSHOTS + 1);
+        let latest = game.snapshot_at(MAX_SNAPSHOTS - 1).unwrap();
+        assert_eq!(latest.board, game.board);
+    }
+
+    #[test]
+    fn cats_game_needs_a_full_board_without_a_line() {
+        use Player::{O, X};
+        let full_draw = [
+            (TileId::A1, X),
+            (TileId::B1, O),
+            (TileId::C1, O),
+            (TileId::A2, O),
+            (TileId::B2, X),
+            (TileId::C2, X),
+            (TileId::A3, O),
+            (TileId::B3, X),
+            (TileId::C3, O),
+        ];
+        assert!(game_with(&full_draw).is_cats_game());
+
+        // Not full yet
+        assert!(!game_with(&full_draw[..8]).is_cats_game());
+
+        // Full, but X got there on the last move
+        let mut full_win = full_draw;
+        full_win[8] = (TileId::C3, X);
+        assert!(!game_with(&full_win).is_cats_game());
+    }
+
+    #[test]
+    fn can_play_gives_each_reason() {
+        use Player::{O, X};
+        let mut game = Game::new(O);
+        assert_eq!(game.can_play(TileId::B2, O), Ok(()));
+        assert_eq!(
+            game.can_play(TileId::B2, X),
+            Err(MoveError::NotYourTurn { turn: O })
+        );
+        let off_board = TileId::new(3, 0);
+        assert_eq!(
+            game.can_play(off_board, O),
+            Err(MoveError::OutOfBounds(off_board))
+        );
+
+        game.play(TileId::B2, O).unwrap();
+        assert_eq!(
+            game.can_play(TileId::B2, X),
+            Err(MoveError::Occupied {
+                tile: TileId::B2,
+                by: O
+            })
+        );
+        // A rejected move changes nothing
+        assert!(game.play(TileId::B2, X).is_err());
+        assert_eq!(game.moves.len(), 1);
+        assert!(matches!(game.state, State::Playing(X)));
+
+        game.forfeit(X);
+        assert_eq!(
+            game.can_play(TileId::A1, X),
+            Err(MoveError::Concluded(Conclusion::Win(O)))
+        );
+    }
+
+    #[test]
+    fn available_moves_are_the_open_tiles() {
+        let game = Game::default();
+        assert_eq!(game.available_moves().len(), 9);
+        assert!(!game.board.is_full());
+
+        let game = game_with(&[(TileId::A1, Player::O), (TileId::B2, Player::X)]);
+        let open = game.available_moves();
+        assert_eq!(open.len(), 7);
+        assert!(!open.contains(&TileId::A1) && !open.contains(&TileId::B2));
+        assert!(open.contains(&TileId::C3));
+        assert!(!game.board.is_full());
+
+        let mut full = Game::default();
+        for (tile, player) in [
+            TileId::A1,
+            TileId::B1,
+            TileId::C1,
+            TileId::A2,
+            TileId::B2,
+            TileId::C2,
+            TileId::A3,
+            TileId::B3,
+            TileId::C3,
+        ]
+        .into_iter()
+        .zip([Player::O, Player::X].into_iter().cycle())
+        {
+            full.board.mark(tile, player);
+        }
+        assert!(full.available_moves().is_empty());
+        assert!(full.board.is_full());
+    }
+
+    #[test]
+    fn conclusions_and_states_read_plainly() {
+        assert_eq!(Conclusion::Win(Player::X).to_string(), "X wins!");
+        assert_eq!(Conclusion::Win(Player::O).to_string(), "O wins!");
+        assert_eq!(Conclusion::Draw.to_string(), "Draw");
+        assert_eq!(State::Playing(Player::X).to_string(), "X to move");
+        assert_eq!(State::Concluded(Conclusion::Draw).to_string(), "game over");
+    }
+
+    #[test]
+    fn open_lines_shrink_as_the_opponent_blocks() {
+        let game = Game::default();
+        assert_eq!(game.open_lines_for(Player::O), 8);
+        assert_eq!(game.open_lines_for(Player::X), 8);
+
+        // The center sits on four lines, a corner on three and an edge on two
+        let game = game_with(&[(TileId::B2, Player::X), (TileId::A1, Player::O)]);
+        assert_eq!(game.open_lines_for(Player::O), 4);
+        assert_eq!(game.open_lines_for(Player::X), 5);
+        let game = game_with(&[(TileId::B1, Player::O)]);
+        assert_eq!(game.open_lines_for(Player::X), 6);
+
+        // A player's own marks never close a line for them
+        let game = game_with(&[
+            (TileId::A1, Player::O),
+            (TileId::B2, Player::O),
+            (TileId::C3, Player::O),
+        ]);
+        assert_eq!(game.open_lines_for(Player::O), 8);
+        assert_eq!(game.open_lines_for(Player::X), 0);
+
+        // Drawn, nothing left for anyone
+        let game = game_with(&[
+            (TileId::A1, Player::X),
+            (TileId::B1, Player::O),
+            (TileId::C1, Player::X),
+            (TileId::A2, Player::X),
+            (TileId::B2, Player::O),
+            (TileId::C2, Player::O),
+            (TileId::A3, Player::O),
+            (TileId::B3, Player::X),
+            (TileId::C3, Player::X),
+        ]);
+        assert_eq!(game.open_lines_for(Player::O), 0);
+        assert_eq!(game.open_lines_for(Player::X), 0);
+    }
+
+    #[test]
+    fn open_lines_are_capped() {
+        let game = Game::with_rules(MAX_BOARD_SIZE, 1, Player::O);
+        assert_eq!(game.open_lines_for(Player::O), u8::MAX);
     }
 }
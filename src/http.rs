@@ -0,0 +1,212 @@
+// Read-only HTTP view of the match in the default room for dashboards and the
+// like, everything it knows comes from the same notifications the players get.
+//
+//  GET /board   the current board as JSON
+//  GET /events  every notification as a server-sent event, RON encoded
+use std::fmt::Write as _;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{broadcast, watch},
+};
+
+use crate::{
+    game::Board,
+    log::{info, warn},
+    message::{Notification, RoomId, DEFAULT_ROOM},
+    server::{Acceptor, Broadcast},
+};
+
+// Runs until the server starts shutting down
+pub async fn serve(
+    listener: TcpListener,
+    mut notifications: broadcast::Receiver<Broadcast>,
+    mut shutting_down: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    use broadcast::error::RecvError;
+
+    info!("http listening on {}...", listener.local_addr()?);
+    let mut acceptor = Acceptor::new(listener);
+    let (board_tx, board_rx) = watch::channel(Board::default());
+
+    loop {
+        select! {
+            _ = shutting_down.changed() => return Ok(()),
+            (stream, _) = acceptor.accept() => {
+                let board = board_rx.clone();
+                let events = notifications.resubscribe();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, board, events).await {
+                        warn!("http error: {e}");
+                    }
+                });
+            }
+            notification = notifications.recv() => match notification {
+                Ok((room, _)) if !shown(room) => {}
+                Ok((_, Notification::TurnPlayed { board, .. } | Notification::GameConcluded { board, .. })) => {
+                    board_tx.send_replace(board);
+                }
+                Ok((_, Notification::BoardSize { n, .. })) => {
+                    board_tx.send_replace(Board::new(n));
+                }
+                Ok((_, Notification::NewGame { .. })) => {
+                    board_tx.send_modify(|board| *board = Board::new(board.size()));
+                }
+                Ok((_, Notification::ServerRestarted)) => {
+                    board_tx.send_replace(Board::default());
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    board: watch::Receiver<Board>,
+    mut events: broadcast::Receiver<Broadcast>,
+) -> anyhow::Result<()> {
+    use broadcast::error::RecvError;
+
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    // Nothing here cares about the headers, just skip past them
+    let mut header = String::new();
+    while stream.read_line(&mut header).await? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/board")) => {
+            let body = board_json(&board.borrow());
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await?;
+        }
+        (Some("GET"), Some("/events")) => {
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n")
+                .await?;
+            loop {
+                let notification = match events.recv().await {
+                    Ok((room, notification)) if shown(room) => notification,
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let Ok(data) = ron::to_string(&notification) else {
+                    continue;
+                };
+                stream
+                    .write_all(format!("data: {data}\n\n").as_bytes())
+                    .await?;
+            }
+        }
+        _ => {
+            stream
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+        }
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Whether a notification is for the default room, or for everyone
+fn shown(room: Option<RoomId>) -> bool {
+    room.is_none_or(|room| room == DEFAULT_ROOM)
+}
+
+// {"size":3,"tiles":["O",null,...]} with tiles row by row from A1
+fn board_json(board: &Board) -> String {
+    let mut json = format!("{{\"size\":{},\"tiles\":[", board.size());
+    for (i, (_, mark)) in board.into_iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        match mark {
+            Some(player) => write!(json, "\"{player}\"").expect("writing to a String cannot fail"),
+            None => json.push_str("null"),
+        }
+    }
+    json.push_str("]}");
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{io::AsyncReadExt, time::timeout};
+
+    use super::*;
+    use crate::game::{Player, TileId};
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn board_is_served_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (notify, notifications) = broadcast::channel(8);
+        let (shutdown, shutting_down) = watch::channel(false);
+        let http = tokio::spawn(serve(listener, notifications, shutting_down));
+
+        let response = get(addr, "/board").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json"));
+        assert!(response
+            .ends_with(r#"{"size":3,"tiles":[null,null,null,null,null,null,null,null,null]}"#));
+
+        let mut board = Board::new(3);
+        board.mark("a1".parse::<TileId>().unwrap(), Player::O);
+        // Other rooms don't show
+        notify
+            .send((Some(1), Notification::BoardSize { n: 4, k: 4 }))
+            .unwrap();
+        notify
+            .send((
+                Some(DEFAULT_ROOM),
+                Notification::TurnPlayed {
+                    board,
+                    next: Some(Player::X),
+                },
+            ))
+            .unwrap();
+        tokio::task::yield_now().await;
+        let response = get(addr, "/board").await;
+        assert!(response
+            .ends_with(r#"{"size":3,"tiles":["O",null,null,null,null,null,null,null,null]}"#));
+
+        assert!(get(addr, "/nope")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found"));
+
+        shutdown.send(true).unwrap();
+        timeout(Duration::from_secs(5), http)
+            .await
+            .expect("stops on shutdown")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+// Leveled logging to stderr, filtered by RUST_LOG (error, warn, info, debug or
+// off, info if unset). Use the error!, warn!, info! and debug! macros.
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn label(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+static MAX_LEVEL: OnceLock<Option<Level>> = OnceLock::new();
+
+fn max_level_from_env() -> Option<Level> {
+    let filter = std::env::var("RUST_LOG").unwrap_or_default();
+    match filter.trim().to_ascii_lowercase().as_str() {
+        "off" => None,
+        "error" => Some(Level::Error),
+        "warn" => Some(Level::Warn),
+        "debug" | "trace" => Some(Level::Debug),
+        _ => Some(Level::Info),
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    MAX_LEVEL
+        .get_or_init(max_level_from_env)
+        .is_some_and(|max| level <= max)
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            eprintln!("{} {}", $level.label(), format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Error, $($arg)*) };
+}
+
+// Named so it doesn't clash with the built-in #[warn] attribute
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Debug, $($arg)*) };
+}
+
+pub(crate) use {debug, error, info, log, log_warn as warn};
@@ -1,14 +1,73 @@
 #![allow(dead_code)]
 
 mod board;
+mod bot;
+mod chat;
 mod connection;
 mod game;
+#[cfg(feature = "http")]
+mod http;
+mod log;
 mod message;
+mod score;
 mod server;
 mod term;
-use tokio;
+mod text;
+
+use crate::log::{error, warn};
+
+// Address to host on when none is given on the command line
+const ADDR_ENV: &str = "TTT_ADDR";
+// Set to also accept plain text clients (nc, telnet) on this address
+const TEXT_ADDR_ENV: &str = "TTT_TEXT_ADDR";
 
 #[tokio::main]
 async fn main() {
-    server::run().await;
+    // `tic-tac-toe connect [addr]` plays on a server, `tic-tac-toe [addr]` hosts
+    // one, on $TTT_ADDR or 127.0.0.1:6969 if no address is given
+    let mut args = std::env::args().skip(1);
+    let first = args.next();
+    if first.as_deref() == Some("connect") {
+        let addr = args
+            .next()
+            .unwrap_or_else(|| server::DEFAULT_ADDR.to_string());
+        match addr.parse() {
+            Ok(addr) => {
+                if let Err(e) = term::play_online(addr).await {
+                    error!("{e}");
+                }
+            }
+            Err(e) => error!("invalid address {addr}: {e}"),
+        }
+        return;
+    }
+
+    let addr = first
+        .or_else(|| std::env::var(ADDR_ENV).ok())
+        .unwrap_or_else(|| server::DEFAULT_ADDR.to_string());
+    let text_addr = match std::env::var(TEXT_ADDR_ENV).ok().map(|a| a.parse()) {
+        Some(Ok(text_addr)) => Some(text_addr),
+        Some(Err(e)) => {
+            error!("invalid {TEXT_ADDR_ENV}: {e}");
+            return;
+        }
+        None => None,
+    };
+    match addr.parse() {
+        Ok(addr) => {
+            if let Err(e) = server::run(addr, text_addr, ctrl_c()).await {
+                error!("{e}");
+            }
+        }
+        Err(e) => error!("invalid address {addr}: {e}"),
+    }
+}
+
+// Resolves on Ctrl-C. If the handler can't be installed the server just runs
+// until it's killed, as it always used to.
+async fn ctrl_c() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("can't listen for Ctrl-C: {e}");
+        std::future::pending::<()>().await;
+    }
 }
@@ -1,6 +1,27 @@
-use crate::game::{Conclusion, Game, Player};
+use std::{collections::HashMap, time::Duration};
+
+use crate::connection::ConnectionId;
+use crate::game::{Board, Conclusion, Game, MoveError, Player, TileId};
 use serde::{Deserialize, Serialize};
 
+// Bumped whenever the wire format changes, 2 sends boards in their compact form
+pub const PROTOCOL_VERSION: u32 = 2;
+
+pub const MAX_CHAT_LEN: usize = 512;
+
+pub const MAX_NAME_LEN: usize = 32;
+
+pub type RoomId = u32;
+
+// Where every connection starts out
+pub const DEFAULT_ROOM: RoomId = 0;
+
+// Optional features the server can enable for a connection. Clients advertise
+// theirs in Request::Hello and only the common subset is turned on.
+pub const SERVER_CAPABILITIES: &[&str] = &[CAPABILITY_MOVE_TOKENS];
+
+pub const CAPABILITY_MOVE_TOKENS: &str = "move-tokens";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     Request(Request),
@@ -10,38 +31,308 @@ pub enum Message {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
+    // Has to come first, anything else but GetVersion is turned away until the
+    // server answers with Response::Welcome
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    // Move to another room, which is created if no one is in it. Giving up a
+    // seat in the old room forfeits the game there, like disconnecting does.
+    JoinRoom(RoomId),
+    // Receive every notification from every room, answered with
+    // Response::Subscribed. The connection can't chat, play or change rooms
+    // afterwards, it only listens.
+    SubscribeAll,
     JoinMatch(Option<Player>),
+    // Take any open seat, Response::Joined says which
+    QuickJoin,
+    GetVersion,
+    // Echoed back in Response::PongTs, to measure latency and clock offset
+    PingTs(u64),
     GetGameInfo,
+    GetMatchResult,
+    // The board after the first n moves of the current game
+    GetBoardAt(usize),
+    // How long the player on turn has left, for clients that missed the last
+    // TilePlaced
+    GetTurnDeadline,
+    GetMyStats,
+    // Forgets every player's stats. Only for the default room's host, or
+    // connections from loopback.
+    ClearScoreboard,
+    SpectatorCount,
+    // Everyone in our room and what they're doing there
+    ListPlayers,
+    // Only answered for connections from loopback
+    DebugState,
+    // Also loopback only. Ends every game, resets every room and the
+    // scoreboard, but keeps everyone connected and in their room.
+    RestartServer,
+    // TODO: ListSavedGames, once finished games are actually saved somewhere.
+    // There's no save directory or on-disk format to list yet.
     Chat(String),
+    Whisper {
+        to: ConnectionId,
+        msg: String,
+    },
+    // Shown to others instead of our address, at most MAX_NAME_LEN characters
+    SetName(String),
     PlayTurn(u8),
+    // What the board would look like after a move, without playing it
+    PreviewMove(u8),
+    MarkAt {
+        row: u8,
+        col: u8,
+    },
+    // Like PlayTurn, but resending the same token for a player is ignored
+    PlayTurnWithToken {
+        tile: u8,
+        token: u64,
+    },
+    SetTurnTimeLimit(Option<Duration>),
+    // Chess clock for the whole game, None turns it off
+    SetTimeBank(Option<TimeBank>),
+    // An n×n board where k in a row wins, 3 and 3 for the classic game
+    SetBoardSize {
+        n: u8,
+        k: u8,
+    },
+    SetObserverChat(bool),
+    TransferHost(ConnectionId),
+    // TODO: SetVisibility(Public | Private) to hide a room from ListRooms, and
+    // SetRoomName(String) for a friendlier name there. Rooms are only known by
+    // id and there's no ListRooms yet to hide them from or show a name in.
+    // TODO: ReserveSeat { player, nickname } so the host can hold a seat for a
+    // tournament entrant, seating them on join and turning others away.
+    // SetName gives us nicknames to match against, but anyone can pick any
+    // name, so a reservation would need some proof of who the entrant is first.
+    Pause,
+    Resume,
+    ClaimDraw,
+    // Concede the game to the other player
+    Resign,
+    // Start a new game with the same players once the current one is over
+    Rematch,
     Disconnect,
 }
 
+impl Request {
+    // Capability that has to be negotiated before this request can be used
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            Request::PlayTurnWithToken { .. } => Some(CAPABILITY_MOVE_TOKENS),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
     Ack,
-    GameInfo(Game),
-    Joined(Option<Player>),
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    GameInfo {
+        game: Game,
+        moves_left: u8,
+    },
+    Version {
+        crate_version: String,
+        protocol_version: u32,
+    },
+    PongTs {
+        client_ts: u64,
+        // Milliseconds since the Unix epoch
+        server_ts: u64,
+    },
+    RoomJoined(RoomId),
+    Subscribed,
+    Joined {
+        side: Option<Player>,
+        // Name of whoever holds the other seat, if anyone
+        opponent: Option<String>,
+        // Whether this join filled the last seat and started the match
+        starting: bool,
+    },
     TurnDone(Game),
+    DuplicateMove(u64),
+    Preview(Board),
+    BoardInfo(Board),
+    TurnDeadline {
+        player: Player,
+        ms_remaining: u64,
+    },
+    DebugState(ServerDebugState),
+    SpectatorCount(u32),
+    Roster(Vec<RosterEntry>),
+    PlayerStats {
+        wins: u32,
+        losses: u32,
+        draws: u32,
+        games: u32,
+        win_rate: f32,
+    },
     GameConcluded(Conclusion),
+    MatchResult {
+        conclusion: Conclusion,
+        final_board: Board,
+        winning_line: Option<Vec<TileId>>,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Group {
+    Host(Option<Player>),
+    Observer,
+    Player(Player),
+    // Listens in on every room without taking part in any
+    Subscriber,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RosterEntry {
+    pub id: ConnectionId,
+    // Chosen with SetName, or the address
+    pub name: String,
+    pub group: Group,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceEntry {
+    pub id: ConnectionId,
+    // Always None for now. PingTs is timed by the client, the server has no
+    // ping of its own to time a round trip with yet.
+    pub rtt_ms: Option<u64>,
+    // Since it last sent anything
+    pub idle_ms: u64,
+}
+
+// Each player starts with `initial` and gets `increment` back after every move
+// they make. Whoever runs out first loses.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TimeBank {
+    pub initial: Duration,
+    pub increment: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerDebugState {
+    pub phase: String,
+    // One line per connection: id, address, room and group
+    pub connections: Vec<String>,
+    pub pending_requests: usize,
+    pub queued_notifications: usize,
+    // Chat kept for the room, and how many messages were evicted to stay within
+    // its budget
+    pub chat_history_bytes: usize,
+    pub chat_evicted: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Notification {
-    Chat { from: String, msg: String },
+    Chat {
+        from: String,
+        msg: String,
+    },
+    Whisper {
+        from: String,
+        msg: String,
+    },
     ServerInfo(String),
+    // Someone took a seat, or joined as host without one
+    PlayerJoined {
+        who: String,
+        as_player: Option<Player>,
+    },
+    // Someone disconnected or moved to another room
+    PlayerLeft {
+        who: String,
+    },
+    NameChanged {
+        old: String,
+        new: String,
+    },
+    // Everyone in the room and how responsive they are, sent periodically
+    Presence(Vec<PresenceEntry>),
+    TurnTimeLimit(Option<Duration>),
+    TimeBank(Option<TimeBank>),
+    BoardSize {
+        n: u8,
+        k: u8,
+    },
+    Paused(bool),
+    // The player on turn is about to run out of time, sent once per move
+    TimeWarning {
+        player: Player,
+        remaining: Duration,
+    },
+    TilePlaced {
+        player: Player,
+        tile: TileId,
+        // Time each player has left, if the game is played with a time bank
+        time_left: Option<HashMap<Player, Duration>>,
+    },
+    // Full board after every move, so observers that joined mid-game can stay
+    // in sync without knowing the earlier moves. `next` is None once the game
+    // is over.
+    TurnPlayed {
+        board: Board,
+        next: Option<Player>,
+    },
+    GameConcluded {
+        conclusion: Conclusion,
+        board: Board,
+    },
+    ScoreboardCleared,
+    // Every room is back to waiting for a host, with no one seated
+    ServerRestarted,
+    // Sent after every move that doesn't end the game
+    TurnChanged {
+        now_playing: Player,
+    },
+    // The board was cleared for a rematch
+    NewGame {
+        first_turn: Player,
+    },
+}
+
+impl Notification {
+    // Whether a client needs this to keep its view of the game correct. Anything
+    // else can be dropped for clients that can't keep up.
+    pub fn is_critical(&self) -> bool {
+        !matches!(
+            self,
+            Notification::Chat { .. }
+                | Notification::Whisper { .. }
+                | Notification::ServerInfo(_)
+                | Notification::Presence(_)
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     WaitingForHost,
     InvalidTile,
-    NotYourTurn,
+    // Someone already marked this tile
+    TileOccupied { tile: TileId, by: Player },
+    NotYourTurn { turn: Player },
+    // Only seated players can play, JoinMatch first
+    NotSeated,
     NotAllowed,
     MatchInProgress,
     GameConcluded(Conclusion),
+    AlreadyJoined,
+    ServerFull,
+    MessageTooLong { max: usize },
+    NoSuchConnection(ConnectionId),
+    NotImplemented,
     InvalidParam(String),
     InvalidMessage(String),
+    // Anything unexpected that doesn't warrant its own variant
     ServerError(String),
 }
 
@@ -64,8 +355,78 @@ impl From<Response> for Message {
     }
 }
 
+impl From<MoveError> for Error {
+    fn from(value: MoveError) -> Self {
+        match value {
+            MoveError::Concluded(conclusion) => Error::GameConcluded(conclusion),
+            MoveError::NotYourTurn { turn } => Error::NotYourTurn { turn },
+            MoveError::OutOfBounds(_) => Error::InvalidTile,
+            MoveError::Occupied { tile, by } => Error::TileOccupied { tile, by },
+        }
+    }
+}
+
 impl From<Error> for Message {
     fn from(value: Error) -> Self {
         Message::Response(Err(value))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_round_trip() {
+        let errors = [
+            Error::WaitingForHost,
+            Error::InvalidTile,
+            Error::TileOccupied {
+                tile: TileId::B2,
+                by: Player::X,
+            },
+            Error::NotYourTurn { turn: Player::O },
+            Error::NotSeated,
+            Error::NotAllowed,
+            Error::MatchInProgress,
+            Error::GameConcluded(Conclusion::Win(Player::X)),
+            Error::AlreadyJoined,
+            Error::ServerFull,
+            Error::MessageTooLong { max: MAX_CHAT_LEN },
+            Error::NoSuchConnection(7),
+            Error::NotImplemented,
+            Error::InvalidParam("side X is taken".to_string()),
+            Error::InvalidMessage("expected Hello first".to_string()),
+            Error::ServerError("request dropped".to_string()),
+        ];
+        for error in errors {
+            let ron = ron::to_string(&Message::from(error)).unwrap();
+            let parsed: Message = ron::from_str(&ron).unwrap();
+            assert!(matches!(parsed, Message::Response(Err(_))), "{ron}");
+            assert_eq!(ron::to_string(&parsed).unwrap(), ron);
+        }
+    }
+
+    #[test]
+    fn move_errors_keep_their_details() {
+        let occupied = MoveError::Occupied {
+            tile: TileId::C3,
+            by: Player::O,
+        };
+        assert!(matches!(
+            Error::from(occupied),
+            Error::TileOccupied {
+                tile: TileId::C3,
+                by: Player::O
+            }
+        ));
+        assert!(matches!(
+            Error::from(MoveError::Concluded(Conclusion::Draw)),
+            Error::GameConcluded(Conclusion::Draw)
+        ));
+        assert!(matches!(
+            Error::from(MoveError::OutOfBounds(TileId::new(5, 5))),
+            Error::InvalidTile
+        ));
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::game::{Conclusion, Player};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+impl PlayerStats {
+    pub fn games(&self) -> u32 {
+        self.wins + self.losses + self.draws
+    }
+
+    pub fn win_rate(&self) -> f32 {
+        match self.games() {
+            0 => 0.0,
+            games => self.wins as f32 / games as f32,
+        }
+    }
+}
+
+// Results of every game played this session, by player name
+#[derive(Debug, Default)]
+pub struct Scoreboard {
+    stats: HashMap<String, PlayerStats>,
+}
+
+impl Scoreboard {
+    pub fn record(&mut self, conclusion: Conclusion, seats: &HashMap<Player, String>) {
+        for (&player, name) in seats {
+            let stats = self.stats.entry(name.clone()).or_default();
+            match conclusion {
+                Conclusion::Win(winner) if winner == player => stats.wins += 1,
+                Conclusion::Win(_) => stats.losses += 1,
+                Conclusion::Draw => stats.draws += 1,
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> PlayerStats {
+        self.stats.get(name).copied().unwrap_or_default()
+    }
+
+    pub fn clear(&mut self) {
+        self.stats.clear();
+    }
+}
@@ -1,74 +1,172 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    future::Future,
     net::SocketAddr,
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use tokio::{
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpSocket, TcpStream},
     select,
-    sync::{broadcast, mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot, watch},
     task::{AbortHandle, JoinSet},
+    time::{interval, sleep, sleep_until, timeout, Duration, Instant, Sleep},
 };
 
 use crate::{
-    connection::Connection,
-    game::TileId,
-    message::{Error as ErrorResponse, Message, Notification, Request, Response},
+    bot,
+    chat::ChatHistory,
+    connection::{Connection, Framing},
+    game::{TileId, MAX_BOARD_SIZE},
+    log::{debug, error, info, warn},
+    message::{
+        Error as ErrorResponse, Group, Message, Notification, PresenceEntry, Request, Response,
+        RoomId, RosterEntry, ServerDebugState, TimeBank, DEFAULT_ROOM, MAX_CHAT_LEN, MAX_NAME_LEN,
+        PROTOCOL_VERSION, SERVER_CAPABILITIES,
+    },
+    score::Scoreboard,
 };
 use crate::{connection::ConnectionId, game};
 
 async fn handle_connection(mut con: Connection, mut server: ServerHandle) -> anyhow::Result<()> {
-    use broadcast::error::RecvError;
+    let mut outbox = Outbox::default();
+    // Only reset by the client sending something, not by us sending to it. A
+    // write the client doesn't read runs into it too.
+    let idle = sleep(server.idle_timeout);
+    tokio::pin!(idle);
 
     loop {
         select! {
-            notification = server.broadcast.recv() => {
-                match notification {
-                    Ok(notification) => {
-                        con.send(notification).await?;
-                    },
-                    Err(RecvError::Lagged(num_skipped)) => {
-                        println!("connection {} lagged by {} notifications", con.addr, num_skipped);
-                        continue;
-                    },
-                    Err(RecvError::Closed) => anyhow::bail!("server broadcast dropped"),
-                }
+            _ = &mut idle => {
+                info!("connection {} ({}) idle for {:?}, closing", server.conn_id, con.addr, server.idle_timeout);
+                // It may have stopped reading as well, don't wait on it for long
+                timeout(SHUTDOWN_GRACE, con.close(Some("closing idle connection".to_string()))).await??;
+                break;
+            }
+            notification = server.broadcast.recv() => server.take(&mut outbox, notification)?,
+            Some(notification) = server.direct.recv() => outbox.push(notification),
+            _ = server.shutting_down.changed() => {
+                con.close(Some("server shutting down".to_string())).await?;
+                break;
             }
             msg = con.recv() => {
+                idle.as_mut().reset(Instant::now() + server.idle_timeout);
                 match msg? {
                     None => {
-                        println!("con EOF");
+                        debug!("connection {} ({}) reached EOF", server.conn_id, con.addr);
                         break;
                     }
                     Some(Request::Disconnect) => {
-                        println!("disconnect");
+                        debug!("connection {} ({}) asked to disconnect", server.conn_id, con.addr);
+                        con.close(None).await?;
                         break;
                     }
                     Some(req) => {
-                        println!("got request: {:?}", req);
+                        debug!("connection {} ({}) sent {:?}", server.conn_id, con.addr, req);
                         let rsp = server.request(req).await;
-                        con.send(Message::Response(rsp)).await?;
+                        match rsp {
+                            Ok(Response::RoomJoined(room)) => server.room = room,
+                            Ok(Response::Subscribed) => server.all_rooms = true,
+                            _ => {}
+                        }
+                        let rsp = Message::Response(rsp);
+                        send_draining(&mut con, &mut server, &mut outbox, &mut idle, rsp).await?;
                     }
                 };
             }
         }
+
+        loop {
+            server.resync(&mut outbox).await;
+            let Some(notification) = outbox.pop() else {
+                break;
+            };
+            send_draining(&mut con, &mut server, &mut outbox, &mut idle, notification).await?;
+        }
     }
 
     Ok(())
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum Group {
-    Host(Option<game::Player>),
-    Observer,
-    Player(game::Player),
+// Keeps pulling in whatever is broadcast while the write is in progress, so a
+// client that's slow to read falls behind in its outbox, which only drops
+// chatter, rather than in the channel, which drops anything. Gives up once
+// the connection's idle timer runs out.
+async fn send_draining(
+    con: &mut Connection,
+    server: &mut ServerHandle,
+    outbox: &mut Outbox,
+    idle: &mut Pin<&mut Sleep>,
+    msg: impl Into<Message>,
+) -> anyhow::Result<()> {
+    let send = con.send(msg);
+    tokio::pin!(send);
+    loop {
+        select! {
+            sent = &mut send => return Ok(sent?),
+            notification = server.broadcast.recv() => server.take(outbox, notification)?,
+            _ = idle.as_mut() => anyhow::bail!("write stalled until the idle timeout"),
+        }
+    }
+}
+
+const OUTBOX_CAPACITY: usize = 32;
+// Notifications meant for one connection that can wait to be picked up
+const DIRECT_CAPACITY: usize = 32;
+// Recent chat sent to someone joining a room, leaving room for whispers
+const CHAT_REPLAY: usize = DIRECT_CAPACITY / 2;
+// Past this even game updates are given up on, see Outbox::overflowed
+const OUTBOX_LIMIT: usize = 4 * OUTBOX_CAPACITY;
+
+// Notifications waiting to be written to a connection. Once it's full, chatter
+// is dropped oldest first so a slow client still gets every game update.
+#[derive(Debug, Default)]
+struct Outbox {
+    queue: VecDeque<Notification>,
+    // Set when the queue was thrown away for growing past OUTBOX_LIMIT, the
+    // client is resynced the same way as when the broadcast channel lags
+    overflowed: bool,
+}
+
+impl Outbox {
+    fn push(&mut self, notification: Notification) {
+        if self.queue.len() >= OUTBOX_CAPACITY {
+            match self.queue.iter().position(|n| !n.is_critical()) {
+                Some(i) => {
+                    self.queue.remove(i);
+                }
+                None if !notification.is_critical() => return,
+                None if self.queue.len() >= OUTBOX_LIMIT => {
+                    self.queue.clear();
+                    self.overflowed = true;
+                    return;
+                }
+                None => {}
+            }
+        }
+        self.queue.push_back(notification);
+    }
+
+    fn pop(&mut self) -> Option<Notification> {
+        self.queue.pop_front()
+    }
 }
 
 #[derive(Debug)]
 struct ConnectionContext {
+    room: RoomId,
     group: Group,
     addr: SocketAddr,
+    // Chosen with SetName, the address is used until then
+    name: Option<String>,
     abort_handle: AbortHandle,
+    // Notifications meant only for this connection
+    direct: mpsc::Sender<Notification>,
+    last_active: Instant,
+    // Negotiated during the Hello/Welcome handshake, None until it's done
+    peer_protocol: Option<u32>,
+    capabilities: Vec<String>,
 }
 
 impl ConnectionContext {
@@ -78,13 +176,40 @@ impl ConnectionContext {
 
     fn player(&self) -> Option<game::Player> {
         match self.group {
-            Group::Observer => None,
+            Group::Observer | Group::Subscriber => None,
             Group::Host(p) => p,
             Group::Player(p) => Some(p),
         }
     }
+
+    fn display_name(&self) -> String {
+        match (&self.name, self.group) {
+            (Some(name), Group::Host(_)) => format!("{name} (host)"),
+            (Some(name), _) => name.clone(),
+            (None, Group::Observer | Group::Subscriber) => self.addr.to_string(),
+            (None, Group::Player(p)) => p.to_string(),
+            (None, Group::Host(None)) => "host".to_string(),
+            (None, Group::Host(Some(p))) => format!("{p} (host)"),
+        }
+    }
+
+    // Who this is regardless of their role, for coming and going
+    fn who(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.addr.to_string())
+    }
+
+    fn handshaked(&self) -> bool {
+        self.peer_protocol.is_some()
+    }
+
+    fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
 }
 
+// A notification for everyone in a room, or on the whole server if None
+pub type Broadcast = (Option<RoomId>, Notification);
+
 type ContextedRequest = (
     ConnectionId,
     Request,
@@ -94,15 +219,85 @@ type ContextedRequest = (
 #[derive(Debug)]
 struct ServerHandle {
     conn_id: ConnectionId,
+    // Kept in step with the server's idea of which room we're in, anything
+    // broadcast to other rooms is dropped
+    room: RoomId,
+    // Set once the connection subscribes to everything, in every room
+    all_rooms: bool,
+    // Set when broadcasts were missed, until the client has been sent the board
+    lagged: bool,
     req_tx: mpsc::Sender<ContextedRequest>,
-    broadcast: broadcast::Receiver<Notification>,
+    broadcast: broadcast::Receiver<Broadcast>,
+    direct: mpsc::Receiver<Notification>,
+    // Changes once the server starts shutting down
+    shutting_down: watch::Receiver<bool>,
+    idle_timeout: Duration,
 }
 
 impl ServerHandle {
+    fn sees(&self, room: Option<RoomId>) -> bool {
+        self.all_rooms || room.is_none_or(|room| room == self.room)
+    }
+
+    // Queues a broadcast for the client if it's meant for it
+    fn take(
+        &mut self,
+        outbox: &mut Outbox,
+        received: Result<Broadcast, broadcast::error::RecvError>,
+    ) -> anyhow::Result<()> {
+        use broadcast::error::RecvError;
+
+        match received {
+            Ok((room, notification)) if self.sees(room) => outbox.push(notification),
+            Ok(_) => {}
+            Err(RecvError::Lagged(num_skipped)) => {
+                warn!(
+                    "connection {} lagged by {} notifications",
+                    self.conn_id, num_skipped
+                );
+                self.lagged = true;
+            }
+            Err(RecvError::Closed) => anyhow::bail!("server broadcast dropped"),
+        }
+        Ok(())
+    }
+
+    // Whatever was missed, the client ends up with the current board. Only the
+    // game matters here, a client that missed chat just missed chat.
+    async fn resync(&mut self, outbox: &mut Outbox) {
+        let missed = std::mem::take(&mut self.lagged) | std::mem::take(&mut outbox.overflowed);
+        if !missed {
+            return;
+        }
+        let Ok(Response::GameInfo { game, .. }) = self.request(Request::GetGameInfo).await else {
+            return;
+        };
+        let next = match game.state {
+            game::State::Playing(turn) => Some(turn),
+            game::State::Concluded(_) => None,
+        };
+        outbox.push(Notification::TurnPlayed {
+            board: game.board.clone(),
+            next,
+        });
+        if let game::State::Concluded(conclusion) = game.state {
+            outbox.push(Notification::GameConcluded {
+                conclusion,
+                board: game.board,
+            });
+        }
+    }
+
     async fn request(&mut self, req: Request) -> Result<Response, ErrorResponse> {
         let (tx, rx) = oneshot::channel();
-        self.req_tx.send((self.conn_id, req, tx)).await.unwrap();
-        rx.await.unwrap()
+        if self.req_tx.send((self.conn_id, req, tx)).await.is_err() {
+            return Err(ErrorResponse::ServerError(
+                "server is shutting down".to_string(),
+            ));
+        }
+        // The server drops requests from connections it no longer knows about
+        rx.await
+            .unwrap_or_else(|_| Err(ErrorResponse::ServerError("request dropped".to_string())))
     }
 }
 
@@ -111,200 +306,1582 @@ enum ServerState {
     #[default]
     WaitingForHost,
     WaitingForPlayers,
-    Playing(game::Game),
+    Playing(Box<GameSession>),
+}
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:6969";
+
+// How long connections get to close themselves before being dropped
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(100);
+
+// Smallest board the host can ask for, anything less can't be won
+const MIN_BOARD_SIZE: u8 = 3;
+
+// Longest the host can set a time bank's initial time or increment to
+const MAX_TIME_BANK: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Players are warned once they have this little time left for their move
+const TIME_WARNING: Duration = Duration::from_secs(5);
+
+// Everything tied to a single game, dropped when the game is over
+#[derive(Debug)]
+struct GameSession {
+    game: game::Game,
+    // Who's playing each side, as of the start of the game. Names come from who() so
+    // stats follow the person rather than their side or whether they host.
+    seats: HashMap<game::Player, String>,
+    turn_time_limit: Option<Duration>,
+    // Last PlayTurnWithToken token accepted from each player
+    move_tokens: HashMap<game::Player, u64>,
+    // The host can pause alone, the players have to both ask for it
+    paused: bool,
+    pause_votes: HashSet<game::Player>,
+    // Side played by the server itself, if a bot filled the second seat
+    bot: Option<game::Player>,
+    time_bank: Option<TimeBank>,
+    // What's left of each player's bank as of `clock_started`
+    clocks: HashMap<game::Player, Duration>,
+    // When the player on turn started using up their bank
+    clock_started: Instant,
+    // How much of `turn_time_limit` this turn has used, as of `clock_started`
+    turn_used: Duration,
+    // Whether the player on turn has been told they're almost out of time
+    warned: bool,
+    // When the player on turn got the move, or the game was last resumed
+    turn_started: Instant,
+}
+
+impl GameSession {
+    fn new(
+        game: game::Game,
+        seats: HashMap<game::Player, String>,
+        turn_time_limit: Option<Duration>,
+        time_bank: Option<TimeBank>,
+    ) -> Self {
+        let clocks = match time_bank {
+            Some(bank) => HashMap::from([
+                (game::Player::X, bank.initial),
+                (game::Player::O, bank.initial),
+            ]),
+            None => HashMap::new(),
+        };
+        Self {
+            game,
+            seats,
+            turn_time_limit,
+            move_tokens: HashMap::new(),
+            paused: false,
+            pause_votes: HashSet::new(),
+            bot: None,
+            time_bank,
+            clocks,
+            clock_started: Instant::now(),
+            turn_used: Duration::ZERO,
+            warned: false,
+            turn_started: Instant::now(),
+        }
+    }
+
+    // Time `player` has left, including the turn they're in the middle of.
+    // None if the game isn't played with a time bank.
+    fn time_left(&self, player: game::Player) -> Option<Duration> {
+        let bank = *self.clocks.get(&player)?;
+        match self.game.state {
+            game::State::Playing(turn) if turn == player && !self.paused => {
+                Some(bank.saturating_sub(self.clock_started.elapsed()))
+            }
+            _ => Some(bank),
+        }
+    }
+
+    fn time_left_all(&self) -> Option<HashMap<game::Player, Duration>> {
+        self.time_bank?;
+        self.clocks
+            .keys()
+            .map(|&player| Some((player, self.time_left(player)?)))
+            .collect()
+    }
+
+    // Time left for the move being made, None without a turn time limit or
+    // once the game is over
+    fn turn_time_left(&self) -> Option<Duration> {
+        let limit = self.turn_time_limit?;
+        let game::State::Playing(_) = self.game.state else {
+            return None;
+        };
+        let running = match self.paused {
+            true => Duration::ZERO,
+            false => self.clock_started.elapsed(),
+        };
+        Some(limit.saturating_sub(self.turn_used + running))
+    }
+
+    // Whichever of the time bank and the turn time limit runs out first
+    fn deadline(&self, player: game::Player) -> Option<Duration> {
+        let on_turn = matches!(self.game.state, game::State::Playing(turn) if turn == player);
+        let turn_left = self.turn_time_left().filter(|_| on_turn);
+        [self.time_left(player), turn_left]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn out_of_time(&self, player: game::Player) -> bool {
+        self.deadline(player).is_some_and(|left| left.is_zero())
+    }
+
+    // Charges the player on turn for the time they've used so far and restarts
+    // the clock from now
+    fn stop_clock(&mut self) {
+        if let game::State::Playing(turn) = self.game.state {
+            if let Some(left) = self.time_left(turn) {
+                self.clocks.insert(turn, left);
+            }
+            if !self.paused {
+                self.turn_used += self.clock_started.elapsed();
+            }
+        }
+        self.clock_started = Instant::now();
+    }
+
+    // Called once a move has been made, the next turn gets the full limit
+    fn start_turn(&mut self) {
+        self.turn_used = Duration::ZERO;
+        self.warned = false;
+        self.turn_started = Instant::now();
+    }
+}
+
+// Which new connections get to host
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HostPolicy {
+    // Anyone on the same machine as the server
+    #[default]
+    Loopback,
+    // The first to connect while no one else is hosting the default room
+    FirstToConnect,
+    // Everyone, for trusted networks
+    Anyone,
+}
+
+// Sends notifications to everyone in one room
+#[derive(Debug, Clone)]
+struct RoomEvents {
+    room: RoomId,
+    tx: broadcast::Sender<Broadcast>,
 }
 
-// Game flow:
+impl RoomEvents {
+    fn send(&self, notification: Notification) {
+        let _ = self.tx.send((Some(self.room), notification));
+    }
+}
+
+// Game flow, separately in every room:
 //  - wait for two connections
 //      - while waiting, disallow turns but allow chat
 // - the two connections play
 //      - if any connection is lost, the other player automatically wins
 // - additional connections will watch the match
+#[derive(Debug)]
+struct Room {
+    events: RoomEvents,
+    state: ServerState,
+    // Chosen by the host before the match starts, None means no time limit
+    turn_time_limit: Option<Duration>,
+    // Also chosen before the match starts, None means no time bank
+    time_bank: Option<TimeBank>,
+    // Board for the next match, `win_len` in a row wins
+    board_size: u8,
+    win_len: u8,
+    // Whether observers are allowed to chat, toggled by the host
+    observer_chat: bool,
+    chat_history: ChatHistory,
+    // When the first seat was taken, while waiting for the second
+    waiting_since: Option<Instant>,
+}
+
+impl Room {
+    fn new(id: RoomId, broadcast: broadcast::Sender<Broadcast>) -> Self {
+        Self {
+            events: RoomEvents {
+                room: id,
+                tx: broadcast,
+            },
+            state: Default::default(),
+            turn_time_limit: None,
+            time_bank: None,
+            board_size: 3,
+            win_len: 3,
+            observer_chat: true,
+            chat_history: ChatHistory::default(),
+            waiting_since: None,
+        }
+    }
+
+    // Size of the board in play, or of the next one if no game is going
+    fn current_board_size(&self) -> u8 {
+        match &self.state {
+            ServerState::Playing(session) => session.game.board.size(),
+            _ => self.board_size,
+        }
+    }
+
+    // A game with the rules the host picked
+    fn new_game(&self, first_turn: game::Player) -> game::Game {
+        game::Game::with_rules(self.board_size, self.win_len, first_turn)
+    }
+}
+
 #[derive(Debug)]
 struct Server {
-    broadcast: broadcast::Sender<Notification>,
+    // Where clients connect, the http view (if enabled) is on the next port
+    addr: SocketAddr,
+    broadcast: broadcast::Sender<Broadcast>,
     req_rx: mpsc::Receiver<ContextedRequest>,
     req_tx: mpsc::Sender<ContextedRequest>,
     contexts: HashMap<ConnectionId, ConnectionContext>,
     connections: JoinSet<ConnectionId>,
     next_conn_id: ConnectionId,
-    state: ServerState,
+    // The default room is always here, any other is dropped once it's empty
+    rooms: HashMap<RoomId, Room>,
+    scoreboard: Scoreboard,
+    // How long the player on turn can go without sending anything before the
+    // game is considered abandoned
+    abandon_timeout: Duration,
+    // Connections that send nothing for this long are closed
+    idle_timeout: Duration,
+    // Pending connections the OS will queue before we get around to accepting
+    listen_backlog: u32,
+    max_connections: usize,
+    // How every client is expected to delimit its messages
+    framing: Framing,
+    // Where to also accept clients speaking the plain text protocol, if anywhere
+    text_addr: Option<SocketAddr>,
+    host_policy: HostPolicy,
+    // How long a lone player waits for an opponent before a bot takes the
+    // second seat, forever if None
+    bot_fill: Option<Duration>,
+    // How often each room is told who's there and how responsive they are,
+    // never if None
+    presence_interval: Option<Duration>,
+    last_presence: Instant,
+    // Tells every connection task to close its connection
+    shutting_down: watch::Sender<bool>,
 }
 
 impl Default for Server {
     fn default() -> Self {
         let (req_tx, req_rx) = mpsc::channel(32);
         let (broadcast, _) = broadcast::channel(32);
+        let max_connections = 64;
         Self {
+            addr: DEFAULT_ADDR.parse().expect("default address is valid"),
+            rooms: HashMap::from([(DEFAULT_ROOM, Room::new(DEFAULT_ROOM, broadcast.clone()))]),
             broadcast,
             req_rx,
             req_tx,
-            contexts: HashMap::with_capacity(32),
+            contexts: HashMap::with_capacity(max_connections),
             connections: JoinSet::new(),
             next_conn_id: 0,
-            state: Default::default(),
+            scoreboard: Scoreboard::default(),
+            abandon_timeout: Duration::from_secs(5 * 60),
+            idle_timeout: Duration::from_secs(30 * 60),
+            listen_backlog: 1024,
+            max_connections,
+            framing: Framing::default(),
+            text_addr: None,
+            host_policy: HostPolicy::default(),
+            bot_fill: None,
+            presence_interval: Some(Duration::from_secs(15)),
+            last_presence: Instant::now(),
+            shutting_down: watch::Sender::new(false),
         }
     }
 }
 
 impl Server {
-    pub async fn run(mut self) -> anyhow::Result<()> {
-        let listener = TcpListener::bind("127.0.0.1:6969").await.unwrap();
-        println!("listening on {}...", listener.local_addr()?);
+    pub fn with_addr(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_presence_interval(self, presence_interval: Option<Duration>) -> Self {
+        Self {
+            presence_interval,
+            ..self
+        }
+    }
+
+    // How clients on the main address delimit their messages. Clients on the
+    // text address always speak Framing::Text.
+    pub fn with_framing(self, framing: Framing) -> Self {
+        Self { framing, ..self }
+    }
+
+    pub fn with_host_policy(self, host_policy: HostPolicy) -> Self {
+        Self {
+            host_policy,
+            ..self
+        }
+    }
+
+    pub fn with_bot_fill(self, bot_fill: Option<Duration>) -> Self {
+        Self { bot_fill, ..self }
+    }
+
+    pub fn with_abandon_timeout(self, abandon_timeout: Duration) -> Self {
+        Self {
+            abandon_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_idle_timeout(self, idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_listen_backlog(self, listen_backlog: u32) -> Self {
+        Self {
+            listen_backlog,
+            ..self
+        }
+    }
+
+    // Anyone connecting once this many are is told the server is full
+    pub fn with_max_connections(self, max_connections: usize) -> Self {
+        Self {
+            max_connections,
+            contexts: HashMap::with_capacity(max_connections),
+            ..self
+        }
+    }
+
+    // Lets people play by typing commands, see the text module
+    pub fn with_text_addr(self, text_addr: SocketAddr) -> Self {
+        Self {
+            text_addr: Some(text_addr),
+            ..self
+        }
+    }
+
+    // Serves until `shutdown` completes
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
+        let listener = self.listen()?;
+        self.serve(listener, shutdown).await
+    }
+
+    // Binding separately from serving lets the caller find out which port it
+    // got when asking for port 0
+    pub fn listen(&self) -> anyhow::Result<TcpListener> {
+        let addr = self.addr;
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        Ok(socket.listen(self.listen_backlog)?)
+    }
+
+    pub async fn serve(
+        mut self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        let addr = listener.local_addr()?;
+        info!("listening on {}...", addr);
+        let mut text_acceptor = match self.text_addr {
+            Some(text_addr) => {
+                let text_listener = TcpListener::bind(text_addr).await?;
+                info!("text protocol on {}...", text_listener.local_addr()?);
+                Some(Acceptor::new(text_listener))
+            }
+            None => None,
+        };
+        let mut acceptor = Acceptor::new(listener);
+
+        #[cfg(feature = "http")]
+        {
+            // Any free port will do if that's what was asked for the game
+            let http_port = match self.addr.port() {
+                0 => 0,
+                _ => match addr.port().checked_add(1) {
+                    Some(port) => port,
+                    None => anyhow::bail!("no port after {} left for the http server", addr.port()),
+                },
+            };
+            let http_addr = SocketAddr::new(addr.ip(), http_port);
+            let notifications = self.broadcast.subscribe();
+            let shutting_down = self.shutting_down.subscribe();
+            tokio::spawn(async move {
+                let served = match TcpListener::bind(http_addr).await {
+                    Ok(listener) => {
+                        crate::http::serve(listener, notifications, shutting_down).await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = served {
+                    error!("http server stopped: {e}");
+                }
+            });
+        }
 
         #[derive(Debug)]
         enum Action {
-            NewConnection(TcpStream, SocketAddr),
+            NewConnection(TcpStream, SocketAddr, Framing),
             Disconnected(ConnectionId),
             Request(Option<ContextedRequest>),
+            Tick,
+            Shutdown,
         }
 
+        let mut ticker = interval(Duration::from_secs(1));
+        tokio::pin!(shutdown);
+
         loop {
             let action = select! {
-                con = listener.accept() => con.map(|(socket, addr)| Action::NewConnection(socket, addr)).unwrap(),
+                _ = &mut shutdown => Action::Shutdown,
+                (socket, addr) = acceptor.accept() => Action::NewConnection(socket, addr, self.framing),
+                (socket, addr) = accept_text(&mut text_acceptor) => {
+                    Action::NewConnection(socket, addr, Framing::Text)
+                }
                 req = self.req_rx.recv() => Action::Request(req),
-                maybe_join = self.connections.join_next() => {
+                _ = ticker.tick() => Action::Tick,
+                // With nothing to join this would be ready straight away, every time
+                maybe_join = self.connections.join_next(), if !self.connections.is_empty() => {
                     match maybe_join {
-                        // TODO: not handling panics or cancellation
-                        // How to associate id to task without using unstable-tokio?
-                        Some(conn_id) => {
-                            println!("joined {:#?}", conn_id);
-                            Action::Disconnected(conn_id.unwrap())},
+                        Some(Ok(conn_id)) => {
+                            debug!("connection {} task finished", conn_id);
+                            Action::Disconnected(conn_id)
+                        }
+                        // The task panicked or was aborted before it could say which
+                        // connection it was, go by its task id instead
+                        Some(Err(e)) => match self.contexts.iter().find(|(_, cx)| cx.abort_handle.id() == e.id()) {
+                            Some((&conn_id, _)) => {
+                                error!("connection {} task failed: {}", conn_id, e);
+                                Action::Disconnected(conn_id)
+                            }
+                            None => continue,
+                        },
                         None => continue
                     }
                 }
             };
 
-            println!("processing {:#?}", action);
+            // Too frequent to be worth logging
+            if let Action::Tick = action {
+                let rooms: Vec<RoomId> = self.rooms.keys().copied().collect();
+                for room in rooms {
+                    self.check_abandoned(room);
+                    self.check_clocks(room);
+                    self.check_bot_fill(room);
+                }
+                self.check_presence();
+                continue;
+            }
+
+            debug!("processing {:?}", action);
             match action {
-                Action::NewConnection(socket, addr) => self.handle_new_connection(socket, addr),
+                Action::NewConnection(socket, addr, framing) => {
+                    self.handle_new_connection(socket, addr, framing)
+                }
                 Action::Request(Some(req)) => self.handle_request(req),
                 Action::Disconnected(conn_id) => self.handle_disconnect(conn_id),
                 Action::Request(None) => {
                     panic!("unknown error handling requests");
                 }
+                Action::Shutdown => {
+                    self.shut_down().await;
+                    return Ok(());
+                }
+                Action::Tick => unreachable!(),
             };
-            println!("post processing {:#?}", &self);
         }
     }
 
+    // Lets everyone know and then drops every connection
+    async fn shut_down(&mut self) {
+        info!("shutting down");
+        // Each connection says goodbye and closes itself, whatever's left after
+        // the grace period is cut off
+        let _ = self.shutting_down.send(true);
+        let _ = timeout(SHUTDOWN_GRACE, async {
+            while self.connections.join_next().await.is_some() {}
+        })
+        .await;
+        for cx in self.contexts.values() {
+            cx.abort_handle.abort();
+        }
+        while self.connections.join_next().await.is_some() {}
+        self.contexts.clear();
+    }
+
     fn handle_request(&mut self, (conn_id, req, rsp): ContextedRequest) {
         use ErrorResponse::InvalidParam;
-        use Request::{Chat, GetGameInfo, JoinMatch, PlayTurn};
+        use Request::{
+            Chat, ClaimDraw, ClearScoreboard, DebugState, GetBoardAt, GetGameInfo, GetMatchResult,
+            GetMyStats, GetTurnDeadline, GetVersion, Hello, JoinMatch, JoinRoom, ListPlayers,
+            MarkAt, Pause, PingTs, PlayTurn, PlayTurnWithToken, PreviewMove, QuickJoin, Rematch,
+            Resign, RestartServer, Resume, SetBoardSize, SetName, SetObserverChat, SetTimeBank,
+            SetTurnTimeLimit, SpectatorCount, SubscribeAll, TransferHost, Whisper,
+        };
         use Response::{Ack, Joined};
 
-        let Entry::Occupied(cx) = self.contexts.entry(conn_id) else {
-            println!("dropping request {} {:#?}", conn_id, req);
+        let Entry::Occupied(mut cx) = self.contexts.entry(conn_id) else {
+            warn!(
+                "dropping request from unknown connection {}: {:?}",
+                conn_id, req
+            );
+            return;
+        };
+        cx.get_mut().last_active = Instant::now();
+        let room_id = cx.get().room;
+        let room = self
+            .rooms
+            .entry(room_id)
+            .or_insert_with(|| Room::new(room_id, self.broadcast.clone()));
+        let board_size = room.current_board_size();
+
+        // Nothing but the handshake, and asking which version to say hello with,
+        // until the client has said which protocol it speaks
+        if !cx.get().handshaked() && !matches!(req, Hello { .. } | GetVersion) {
+            let _ = rsp.send(Err(ErrorResponse::InvalidMessage(
+                "expected Hello first".to_string(),
+            )));
             return;
+        }
+        let greeting = !cx.get().handshaked() && matches!(req, Hello { .. });
+
+        // MarkAt is just another way to spell PlayTurn, and QuickJoin is JoinMatch
+        // with whatever seat is free. The host picking a side first gets O.
+        let req = match req {
+            QuickJoin => match room.state {
+                ServerState::WaitingForHost => JoinMatch(Some(game::Player::O)),
+                _ => JoinMatch(None),
+            },
+            MarkAt { row, col } if row < board_size && col < board_size => {
+                PlayTurn(row * board_size + col)
+            }
+            MarkAt { row, col } => {
+                let _ = rsp.send(Err(InvalidParam(format!("no tile at ({row}, {col})"))));
+                return;
+            }
+            req => req,
+        };
+
+        if let Some(capability) = req.required_capability() {
+            if !cx.get().has_capability(capability) {
+                let _ = rsp.send(Err(ErrorResponse::NotAllowed));
+                return;
+            }
+        }
+
+        let move_token = match &req {
+            PlayTurnWithToken { token, .. } => Some(*token),
+            _ => None,
         };
+        let player = cx.get().player();
 
         // TODO: this is the ugliest Rust code I've ever written.
-        let r: Result<Response, ErrorResponse> = match (req, &mut self.state) {
+        let r: Result<Response, ErrorResponse> = match (req, &mut room.state) {
+            (Hello { version, .. }, _) if version != PROTOCOL_VERSION => {
+                Err(InvalidParam(format!(
+                    "protocol version {version} isn't supported, the server speaks {PROTOCOL_VERSION}"
+                )))
+            }
+            (
+                Hello {
+                    version,
+                    capabilities,
+                },
+                _,
+            ) => {
+                let capabilities: Vec<String> = capabilities
+                    .into_iter()
+                    .filter(|c| SERVER_CAPABILITIES.contains(&c.as_str()))
+                    .collect();
+                let cx = cx.into_mut();
+                cx.peer_protocol = Some(version);
+                cx.capabilities = capabilities.clone();
+                Ok(Response::Welcome {
+                    version: PROTOCOL_VERSION,
+                    capabilities,
+                })
+            }
+            (SubscribeAll, _) if player.is_some() => Err(ErrorResponse::AlreadyJoined),
+            (SubscribeAll, _) => {
+                cx.into_mut().group = Group::Subscriber;
+                Ok(Response::Subscribed)
+            }
+            (Chat(_) | Whisper { .. } | JoinRoom(_) | JoinMatch(_), _)
+                if cx.get().group == Group::Subscriber =>
+            {
+                Err(ErrorResponse::NotAllowed)
+            }
+            (DebugState, _) if cx.get().addr.ip().is_loopback() => {
+                Ok(Response::DebugState(self.debug_state(room_id)))
+            }
+            (SpectatorCount, _) => Ok(Response::SpectatorCount(
+                self.contexts
+                    .values()
+                    .filter(|cx| cx.room == room_id && cx.group == Group::Observer)
+                    .count() as u32,
+            )),
+            (DebugState, _) => Err(ErrorResponse::NotAllowed),
+            (RestartServer, _) if cx.get().addr.ip().is_loopback() => {
+                self.restart();
+                Ok(Ack)
+            }
+            (RestartServer, _) => Err(ErrorResponse::NotAllowed),
+            (ListPlayers, _) => Ok(Response::Roster(
+                self.sorted_contexts()
+                    .filter(|(_, cx)| cx.room == room_id)
+                    .map(|(id, cx)| RosterEntry {
+                        id,
+                        name: cx.who(),
+                        group: cx.group,
+                    })
+                    .collect(),
+            )),
+            (GetVersion, _) => Ok(Response::Version {
+                crate_version: env!("CARGO_PKG_VERSION").to_string(),
+                protocol_version: PROTOCOL_VERSION,
+            }),
+            (PingTs(client_ts), _) => Ok(Response::PongTs {
+                client_ts,
+                server_ts: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64),
+            }),
+            (Chat(_) | Whisper { .. }, _)
+                if !room.observer_chat && cx.get().group == Group::Observer =>
+            {
+                Err(ErrorResponse::NotAllowed)
+            }
+            (Chat(msg) | Whisper { msg, .. }, _) if msg.len() > MAX_CHAT_LEN => {
+                Err(ErrorResponse::MessageTooLong { max: MAX_CHAT_LEN })
+            }
             (Chat(msg), _) => {
-                let cx = cx.get();
-                let from = match cx.group {
-                    Group::Observer => cx.addr.to_string(),
-                    Group::Player(p) => p.to_string(),
-                    Group::Host(None) => "host".to_string(),
-                    Group::Host(Some(p)) => format!("{p} (host)"),
-                };
-                self.broadcast
-                    .send(Notification::Chat { from, msg })
-                    .unwrap();
+                let from = cx.get().display_name();
+                room.chat_history.push(from.clone(), msg.clone());
+                room.events.send(Notification::Chat { from, msg });
                 Ok(Ack)
             }
 
+            (Whisper { to, msg }, _) => {
+                let from = cx.get().display_name();
+                // Only to someone in the same room, as if they weren't there otherwise
+                let in_room = self.contexts.get(&to).is_some_and(|cx| cx.room == room_id);
+                if in_room && self.notify(to, Notification::Whisper { from, msg }) {
+                    Ok(Ack)
+                } else {
+                    Err(ErrorResponse::NoSuchConnection(to))
+                }
+            }
+            (JoinRoom(to), _) => self.join_room(conn_id, to),
+            (SetName(name), _) => {
+                let name = name.trim();
+                if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+                    Err(InvalidParam(format!(
+                        "names must be 1 to {MAX_NAME_LEN} characters"
+                    )))
+                } else if name.chars().any(char::is_control) {
+                    Err(InvalidParam(
+                        "names cannot contain control characters".to_string(),
+                    ))
+                } else {
+                    let cx = cx.into_mut();
+                    let old = cx.who();
+                    cx.name = Some(name.to_string());
+                    room.events
+                        .send(Notification::NameChanged { old, new: cx.who() });
+                    Ok(Ack)
+                }
+            }
+
             (JoinMatch(player), ServerState::WaitingForHost) if cx.get().is_host() => {
-                cx.into_mut().group = Group::Host(player);
-                self.state = ServerState::WaitingForPlayers;
-                Ok(Joined(player))
+                let cx = cx.into_mut();
+                cx.group = Group::Host(player);
+                room.events.send(Notification::PlayerJoined {
+                    who: cx.who(),
+                    as_player: player,
+                });
+                room.state = ServerState::WaitingForPlayers;
+                if player.is_some() {
+                    room.waiting_since = Some(Instant::now());
+                }
+                Ok(Joined {
+                    side: player,
+                    opponent: None,
+                    starting: false,
+                })
             }
             (JoinMatch(req_join_as), ServerState::WaitingForPlayers) => {
-                // Once the second player joins, the state is moved to Playing
-                match cx.get().group {
-                    group @ (Group::Observer | Group::Host(None)) => {
-                        // Find existing player, if any
-                        let already_joined =
-                            self.contexts.iter().find_map(|id_cx| match id_cx.1.group {
-                                Group::Host(Some(other)) | Group::Player(other) => Some(other),
-                                _ => None,
-                            });
-
-                        let join_as = match (req_join_as, already_joined) {
-                            (None, None) => game::Player::O,
-                            (Some(req_join_as), None) => req_join_as,
-                            (None | Some(_), Some(already_joined)) => !already_joined,
-                        };
-
-                        let new_group = if matches!(group, Group::Observer) {
-                            Group::Player(join_as)
-                        } else {
-                            Group::Host(Some(join_as))
-                        };
-
-                        self.contexts
-                            .entry(conn_id)
-                            .and_modify(|cx| cx.group = new_group);
-
-                        if let Some(_) = already_joined {
-                            self.state = ServerState::Playing(game::Game::new(game::Player::O))
+                let group = cx.get().group;
+                self.join_match(room_id, conn_id, group, req_join_as)
+            }
+            (SetObserverChat(_), _) if !cx.get().is_host() => Err(ErrorResponse::NotAllowed),
+            (SetObserverChat(allowed), _) => {
+                room.observer_chat = allowed;
+                Ok(Ack)
+            }
+            (TransferHost(_), _) if !cx.get().is_host() => Err(ErrorResponse::NotAllowed),
+            (TransferHost(to), _) => self.transfer_host(conn_id, to),
+            (SetTurnTimeLimit(_), _) if !cx.get().is_host() => Err(ErrorResponse::NotAllowed),
+            (
+                SetTurnTimeLimit(limit),
+                ServerState::WaitingForHost | ServerState::WaitingForPlayers,
+            ) => {
+                room.turn_time_limit = limit;
+                room.events.send(Notification::TurnTimeLimit(limit));
+                Ok(Ack)
+            }
+            (SetTurnTimeLimit(_), ServerState::Playing(_)) => Err(ErrorResponse::MatchInProgress),
+            (SetTimeBank(_), _) if !cx.get().is_host() => Err(ErrorResponse::NotAllowed),
+            (SetTimeBank(Some(bank)), _)
+                if bank.initial > MAX_TIME_BANK || bank.increment > MAX_TIME_BANK =>
+            {
+                Err(InvalidParam(format!(
+                    "time bank initial and increment can be at most {MAX_TIME_BANK:?}"
+                )))
+            }
+            (SetTimeBank(bank), ServerState::WaitingForHost | ServerState::WaitingForPlayers) => {
+                room.time_bank = bank;
+                room.events.send(Notification::TimeBank(bank));
+                Ok(Ack)
+            }
+            (SetTimeBank(_), ServerState::Playing(_)) => Err(ErrorResponse::MatchInProgress),
+            (SetBoardSize { .. }, _) if !cx.get().is_host() => Err(ErrorResponse::NotAllowed),
+            (
+                SetBoardSize { n, k },
+                ServerState::WaitingForHost | ServerState::WaitingForPlayers,
+            ) => {
+                if !(MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(&n) {
+                    Err(InvalidParam(format!(
+                        "board size must be between {MIN_BOARD_SIZE} and {MAX_BOARD_SIZE}"
+                    )))
+                } else if !(MIN_BOARD_SIZE..=n).contains(&k) {
+                    Err(InvalidParam(format!(
+                        "win length must be between {MIN_BOARD_SIZE} and the board size"
+                    )))
+                } else {
+                    room.board_size = n;
+                    room.win_len = k;
+                    room.events.send(Notification::BoardSize { n, k });
+                    Ok(Ack)
+                }
+            }
+            (SetBoardSize { .. }, ServerState::Playing(_)) => Err(ErrorResponse::MatchInProgress),
+            (GetGameInfo, ServerState::Playing(session)) => Ok(Response::GameInfo {
+                game: session.game.clone(),
+                moves_left: session.game.legal_move_count(),
+            }),
+            (Pause, ServerState::Playing(session)) => {
+                let cx = cx.get();
+                match (cx.is_host(), cx.player()) {
+                    (false, None) => Err(ErrorResponse::NotAllowed),
+                    (is_host, player) => {
+                        if let Some(player) = player {
+                            session.pause_votes.insert(player);
                         }
-
-                        Ok(Response::Joined(Some(join_as)))
+                        if !session.paused && (is_host || session.pause_votes.len() == 2) {
+                            session.stop_clock();
+                            session.paused = true;
+                            room.events.send(Notification::Paused(true));
+                        }
+                        Ok(Ack)
                     }
-                    Group::Player(_) | Group::Host(Some(_)) => {
-                        Err(InvalidParam("already joined".to_string()))
+                }
+            }
+            (Resume, ServerState::Playing(session))
+                if cx.get().is_host() || cx.get().player().is_some() =>
+            {
+                session.pause_votes.clear();
+                if session.paused {
+                    // Restart the clock before unpausing so the pause isn't charged
+                    session.stop_clock();
+                    session.paused = false;
+                    session.turn_started = Instant::now();
+                    room.events.send(Notification::Paused(false));
+                }
+                Ok(Ack)
+            }
+            (Pause | Resume, _) => Err(ErrorResponse::NotAllowed),
+            // Draws can only be claimed once neither side can complete a line
+            // whatever happens. Solving the position would allow more of them, but
+            // is too slow to do in the server loop.
+            (ClaimDraw, ServerState::Playing(session))
+                if player.is_some()
+                    && matches!(session.game.state, game::State::Playing(_))
+                    && session.game.open_lines_for(game::Player::O) == 0
+                    && session.game.open_lines_for(game::Player::X) == 0 =>
+            {
+                let conclusion = game::Conclusion::Draw;
+                session.game.conclude(conclusion);
+                self.game_concluded(room_id, conclusion);
+                Ok(Response::GameConcluded(conclusion))
+            }
+            (ClaimDraw, _) => Err(ErrorResponse::NotAllowed),
+            (Resign, ServerState::Playing(session)) => match (player, &session.game.state) {
+                (None, _) => Err(ErrorResponse::NotAllowed),
+                (_, game::State::Concluded(conclusion)) => {
+                    Err(ErrorResponse::GameConcluded(*conclusion))
+                }
+                (Some(player), game::State::Playing(_)) => {
+                    let conclusion = game::Conclusion::Win(!player);
+                    session.game.conclude(conclusion);
+                    room.events
+                        .send(Notification::ServerInfo(format!("{player} resigned")));
+                    self.game_concluded(room_id, conclusion);
+                    Ok(Response::GameConcluded(conclusion))
+                }
+            },
+            (Resign, _) => Err(ErrorResponse::NotAllowed),
+            (Rematch, ServerState::Playing(session)) => match session.game.state {
+                game::State::Playing(_) => Err(ErrorResponse::MatchInProgress),
+                game::State::Concluded(_) if !cx.get().is_host() && player.is_none() => {
+                    Err(ErrorResponse::NotAllowed)
+                }
+                game::State::Concluded(conclusion) => {
+                    let seated = |side| {
+                        session.bot == Some(side)
+                            || self
+                                .contexts
+                                .values()
+                                .any(|cx| cx.room == room_id && cx.player() == Some(side))
+                    };
+                    let missing = [game::Player::O, game::Player::X]
+                        .into_iter()
+                        .find(|&side| !seated(side));
+                    if let Some(missing) = missing {
+                        Err(InvalidParam(format!(
+                            "{missing} has left, wait for someone to take their seat"
+                        )))
+                    } else {
+                        // The loser gets to go first
+                        let first_turn = match conclusion {
+                            game::Conclusion::Win(winner) => !winner,
+                            game::Conclusion::Draw => game::Player::O,
+                        };
+                        let game = &session.game;
+                        let mut rematch = GameSession::new(
+                            game::Game::with_rules(game.board.size(), game.win_len, first_turn),
+                            session.seats.clone(),
+                            session.turn_time_limit,
+                            session.time_bank,
+                        );
+                        rematch.bot = session.bot;
+                        **session = rematch;
+                        room.events.send(Notification::NewGame { first_turn });
+                        Ok(Ack)
                     }
                 }
+            },
+            (Rematch, _) => Err(ErrorResponse::NotAllowed),
+            (PlayTurn(_) | PlayTurnWithToken { .. } | PreviewMove(_), _) if player.is_none() => {
+                Err(ErrorResponse::NotSeated)
             }
-            (GetGameInfo, ServerState::Playing(game)) => Ok(Response::GameInfo(game.clone())),
-            (PlayTurn(tile), ServerState::Playing(game)) => {
-                if let Some(player) = cx.get().player() {
-                    match game.state {
-                        game::State::Playing(turn) => {
-                            if turn != player {
-                                Err(ErrorResponse::NotYourTurn)
-                            } else if let Ok(tile_id) = TileId::try_from(tile) {
-                                if !game.try_turn(tile_id) {
-                                    Err(ErrorResponse::InvalidTile)
-                                } else if let Some(conclusion) = game.has_game_concluded() {
-                                    Ok(Response::GameConcluded(conclusion))
-                                } else {
-                                    Ok(Response::TurnDone(game.clone()))
-                                }
-                            } else {
-                                Err(ErrorResponse::InvalidTile)
-                            }
+            (PlayTurn(_) | PlayTurnWithToken { .. }, ServerState::Playing(session))
+                if session.paused =>
+            {
+                Err(ErrorResponse::NotAllowed)
+            }
+            (PlayTurnWithToken { token, .. }, ServerState::Playing(session))
+                if player.is_some_and(|p| session.move_tokens.get(&p) == Some(&token)) =>
+            {
+                Ok(Response::DuplicateMove(token))
+            }
+            (
+                PlayTurn(index) | PlayTurnWithToken { tile: index, .. },
+                ServerState::Playing(session),
+            ) => match (player, session.game.board.tile_at(index)) {
+                (None, _) => Err(ErrorResponse::NotSeated),
+                (_, None) => Err(ErrorResponse::InvalidTile),
+                (Some(player), Some(tile)) => self.play_turn(room_id, player, tile),
+            },
+            (PreviewMove(index), ServerState::Playing(session)) => {
+                let mut game = session.game.clone();
+                match (player, game.board.tile_at(index)) {
+                    (None, _) => Err(ErrorResponse::NotSeated),
+                    (_, None) => Err(ErrorResponse::InvalidTile),
+                    (Some(player), Some(tile_id)) => match game.can_play(tile_id, player) {
+                        Err(e) => Err(e.into()),
+                        Ok(()) => {
+                            game.try_turn(tile_id);
+                            Ok(Response::Preview(game.board))
                         }
-                        game::State::Concluded(_) => Err(ErrorResponse::NotAllowed),
-                    }
-                } else {
-                    Err(ErrorResponse::NotYourTurn)
+                    },
                 }
             }
-            (PlayTurn(_), _) => Err(ErrorResponse::NotAllowed),
-            (GetGameInfo, _) => Err(ErrorResponse::NotAllowed),
-            (JoinMatch(_), ServerState::Playing(_)) => Err(ErrorResponse::MatchInProgress),
-            (_, ServerState::WaitingForHost) => Err(ErrorResponse::WaitingForHost),
-            _ => Err(ErrorResponse::ServerError("not implemented".to_string())),
-        };
-
-        rsp.send(r).unwrap();
-    }
-
+            (PreviewMove(_), _) => Err(ErrorResponse::NotAllowed),
+            (GetMatchResult, ServerState::Playing(session)) => match session.game.state {
+                game::State::Concluded(conclusion) => {
+                    let game = &session.game;
+                    let winning_line = match conclusion {
+                        game::Conclusion::Win(winner) => {
+                            game.winning_lines_for(winner).into_iter().next()
+                        }
+                        game::Conclusion::Draw => None,
+                    };
+                    Ok(Response::MatchResult {
+                        conclusion,
+                        final_board: game.board.clone(),
+                        winning_line,
+                    })
+                }
+                game::State::Playing(_) => Err(ErrorResponse::NotAllowed),
+            },
+            (GetMatchResult, _) => Err(ErrorResponse::NotAllowed),
+            (GetBoardAt(n), ServerState::Playing(session)) => match session.game.board_at(n) {
+                Some(board) => Ok(Response::BoardInfo(board)),
+                None => Err(InvalidParam(format!(
+                    "only {} moves have been played",
+                    session.game.moves.len()
+                ))),
+            },
+            (GetBoardAt(_), _) => Err(ErrorResponse::NotAllowed),
+            // Without a time bank or turn time limit there's no deadline
+            (GetTurnDeadline, ServerState::Playing(session)) => match session.game.state {
+                game::State::Playing(player) => match session.deadline(player) {
+                    Some(left) => Ok(Response::TurnDeadline {
+                        player,
+                        ms_remaining: left.as_millis() as u64,
+                    }),
+                    None => Err(ErrorResponse::NotAllowed),
+                },
+                game::State::Concluded(_) => Err(ErrorResponse::NotAllowed),
+            },
+            (GetTurnDeadline, _) => Err(ErrorResponse::NotAllowed),
+            (GetMyStats, _) => {
+                let stats = self.scoreboard.get(&cx.get().who());
+                Ok(Response::PlayerStats {
+                    wins: stats.wins,
+                    losses: stats.losses,
+                    draws: stats.draws,
+                    games: stats.games(),
+                    win_rate: stats.win_rate(),
+                })
+            }
+            // The scoreboard is shared by every room, so hosting a room of our own
+            // isn't enough
+            (ClearScoreboard, _)
+                if !(cx.get().addr.ip().is_loopback()
+                    || (cx.get().is_host() && room_id == DEFAULT_ROOM)) =>
+            {
+                Err(ErrorResponse::NotAllowed)
+            }
+            (ClearScoreboard, _) => {
+                self.scoreboard.clear();
+                let _ = self.broadcast.send((None, Notification::ScoreboardCleared));
+                Ok(Ack)
+            }
+            (PlayTurn(_) | PlayTurnWithToken { .. }, _) => Err(ErrorResponse::NotAllowed),
+            (GetGameInfo, _) => Err(ErrorResponse::NotAllowed),
+            (JoinMatch(_), ServerState::Playing(_)) => Err(ErrorResponse::MatchInProgress),
+            (_, ServerState::WaitingForHost) => Err(ErrorResponse::WaitingForHost),
+            _ => Err(ErrorResponse::NotImplemented),
+        };
+
+        self.play_bot_move(room_id);
+
+        // Only once it's said hello, so the Welcome comes first
+        if greeting && r.is_ok() {
+            self.replay_chat(conn_id, room_id);
+        }
+
+        if let (Ok(_), Some(token), Some(player), Some(session)) =
+            (&r, move_token, player, self.session_mut(room_id))
+        {
+            session.move_tokens.insert(player, token);
+        }
+
+        // The connection may have gone away while we were busy, nothing to do then
+        let _ = rsp.send(r);
+    }
+
+    // Seats a connection while waiting for players. Once the second player joins,
+    // the state is moved to Playing.
+    fn join_match(
+        &mut self,
+        room_id: RoomId,
+        conn_id: ConnectionId,
+        group: Group,
+        req_join_as: Option<game::Player>,
+    ) -> Result<Response, ErrorResponse> {
+        // One connection can only ever hold one seat, asking for the other side
+        // as well is a mistake rather than a repeat
+        match (group, req_join_as) {
+            (Group::Player(seat) | Group::Host(Some(seat)), Some(side)) if side != seat => {
+                return Err(ErrorResponse::InvalidParam(format!(
+                    "already playing {seat}, cannot also take {side}"
+                )));
+            }
+            (Group::Player(_) | Group::Host(Some(_)), _) => {
+                return Err(ErrorResponse::AlreadyJoined)
+            }
+            _ => {}
+        }
+
+        // Find existing player, if any. Never this connection, it isn't seated.
+        let (already_joined, opponent) = self
+            .contexts
+            .iter()
+            .filter(|&(&id, cx)| id != conn_id && cx.room == room_id)
+            .find_map(|(_, cx)| match cx.group {
+                Group::Host(Some(other)) | Group::Player(other) => {
+                    Some((Some(other), Some(cx.who())))
+                }
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        let join_as = match (req_join_as, already_joined) {
+            (None, None) => game::Player::O,
+            (Some(req_join_as), None) => req_join_as,
+            (Some(req_join_as), Some(already_joined)) if req_join_as == already_joined => {
+                return Err(ErrorResponse::InvalidParam(format!(
+                    "side {already_joined} is taken"
+                )));
+            }
+            (None | Some(_), Some(already_joined)) => !already_joined,
+        };
+
+        let new_group = if matches!(group, Group::Observer) {
+            Group::Player(join_as)
+        } else {
+            Group::Host(Some(join_as))
+        };
+
+        let Some(cx) = self.contexts.get_mut(&conn_id) else {
+            return Err(ErrorResponse::NoSuchConnection(conn_id));
+        };
+        cx.group = new_group;
+        let who = cx.who();
+
+        let seats = self
+            .contexts
+            .values()
+            .filter(|cx| cx.room == room_id)
+            .filter_map(|cx| Some((cx.player()?, cx.who())))
+            .collect();
+        let room = self.room_mut(room_id);
+        room.events.send(Notification::PlayerJoined {
+            who,
+            as_player: Some(join_as),
+        });
+        if already_joined.is_none() {
+            room.waiting_since = Some(Instant::now());
+        } else {
+            room.waiting_since = None;
+            room.state = ServerState::Playing(Box::new(GameSession::new(
+                room.new_game(game::Player::O),
+                seats,
+                room.turn_time_limit,
+                room.time_bank,
+            )));
+        }
+
+        Ok(Response::Joined {
+            side: Some(join_as),
+            opponent,
+            starting: already_joined.is_some(),
+        })
+    }
+
+    // Hands hosting over to another connection in the same room, both keep their
+    // seats if they have one
+    fn transfer_host(
+        &mut self,
+        from: ConnectionId,
+        to: ConnectionId,
+    ) -> Result<Response, ErrorResponse> {
+        let room_id = self.contexts.get(&from).map_or(DEFAULT_ROOM, |cx| cx.room);
+        let target = self
+            .contexts
+            .get_mut(&to)
+            .filter(|cx| cx.room == room_id)
+            .ok_or(ErrorResponse::NoSuchConnection(to))?;
+        target.group = match target.group {
+            Group::Observer => Group::Host(None),
+            Group::Player(p) => Group::Host(Some(p)),
+            // Already the host
+            Group::Host(_) => return Ok(Response::Ack),
+            Group::Subscriber => return Err(ErrorResponse::NotAllowed),
+        };
+        let new_host = target.display_name();
+
+        if let Some(old) = self.contexts.get_mut(&from) {
+            old.group = match old.group {
+                Group::Host(Some(p)) => Group::Player(p),
+                _ => Group::Observer,
+            };
+        }
+
+        self.room_mut(room_id)
+            .events
+            .send(Notification::ServerInfo(format!(
+                "{new_host} is now hosting"
+            )));
+        Ok(Response::Ack)
+    }
+
+    // Seats a bot opposite a lone player who has been waiting too long, if the
+    // server allows it
+    fn check_bot_fill(&mut self, room_id: RoomId) {
+        let Some(room) = self.rooms.get(&room_id) else {
+            return;
+        };
+        let Some(timeout) = self.bot_fill else {
+            return;
+        };
+        if !matches!(room.state, ServerState::WaitingForPlayers) {
+            return;
+        }
+        let Some(since) = room.waiting_since else {
+            return;
+        };
+        if since.elapsed() < timeout {
+            return;
+        }
+        let Some((player, name)) = self
+            .contexts
+            .values()
+            .filter(|cx| cx.room == room_id)
+            .find_map(|cx| Some((cx.player()?, cx.who())))
+        else {
+            return;
+        };
+
+        let room = self.room_mut(room_id);
+        let seats = HashMap::from([(player, name), (!player, "bot".to_string())]);
+        let mut session = GameSession::new(
+            room.new_game(game::Player::O),
+            seats,
+            room.turn_time_limit,
+            room.time_bank,
+        );
+        session.bot = Some(!player);
+        room.state = ServerState::Playing(Box::new(session));
+        room.waiting_since = None;
+
+        room.events.send(Notification::ServerInfo(format!(
+            "no one else joined, a bot is playing {}",
+            !player
+        )));
+        self.play_bot_move(room_id);
+    }
+
+    fn play_bot_move(&mut self, room_id: RoomId) {
+        let Some(session) = self.session_mut(room_id) else {
+            return;
+        };
+        let game::State::Playing(turn) = session.game.state else {
+            return;
+        };
+        if session.bot != Some(turn) || session.paused {
+            return;
+        }
+
+        if let Some(tile) = bot::best_move(&session.game) {
+            let _ = self.play_turn(room_id, turn, tile);
+        }
+    }
+
+    // Marks a tile for `player` and moves the game along, to the next turn or
+    // to its conclusion
+    fn play_turn(
+        &mut self,
+        room_id: RoomId,
+        player: game::Player,
+        tile: TileId,
+    ) -> Result<Response, ErrorResponse> {
+        let Some(room) = self.rooms.get_mut(&room_id) else {
+            return Err(ErrorResponse::NotAllowed);
+        };
+        let ServerState::Playing(session) = &mut room.state else {
+            return Err(ErrorResponse::NotAllowed);
+        };
+        let events = &room.events;
+
+        if session.out_of_time(player) {
+            if let Some(conclusion) = session.game.forfeit(player) {
+                self.game_concluded(room_id, conclusion);
+                return Err(ErrorResponse::GameConcluded(conclusion));
+            }
+        }
+
+        session.stop_clock();
+        session.game.play(tile, player)?;
+        session.start_turn();
+        if let (Some(bank), Some(left)) = (session.time_bank, session.clocks.get_mut(&player)) {
+            *left = left.saturating_add(bank.increment);
+        }
+        events.send(Notification::TilePlaced {
+            player,
+            tile,
+            time_left: session.time_left_all(),
+        });
+        let next = match session.game.state {
+            game::State::Playing(turn) => Some(turn),
+            game::State::Concluded(_) => None,
+        };
+        events.send(Notification::TurnPlayed {
+            board: session.game.board.clone(),
+            next,
+        });
+        if let Some(now_playing) = next {
+            events.send(Notification::TurnChanged { now_playing });
+        }
+
+        if let game::State::Concluded(conclusion) = session.game.state {
+            self.game_concluded(room_id, conclusion);
+            return Ok(Response::GameConcluded(conclusion));
+        }
+        Ok(Response::TurnDone(session.game.clone()))
+    }
+
+    // Forfeits the game for the player on turn once their time bank is empty or
+    // their move takes longer than the turn time limit, warning them first
+    fn check_clocks(&mut self, room_id: RoomId) {
+        let Some(room) = self.rooms.get_mut(&room_id) else {
+            return;
+        };
+        let ServerState::Playing(session) = &mut room.state else {
+            return;
+        };
+        let game::State::Playing(turn) = session.game.state else {
+            return;
+        };
+        if !session.out_of_time(turn) {
+            if let Some(remaining) = session.deadline(turn) {
+                if remaining <= TIME_WARNING && !session.warned {
+                    session.warned = true;
+                    room.events.send(Notification::TimeWarning {
+                        player: turn,
+                        remaining,
+                    });
+                }
+            }
+            return;
+        }
+
+        if let Some(conclusion) = session.game.forfeit(turn) {
+            room.events
+                .send(Notification::ServerInfo(format!("{turn} ran out of time")));
+            self.game_concluded(room_id, conclusion);
+        }
+    }
+
+    // Ends games the player on turn has walked away from, they forfeit once
+    // they've gone quiet for the whole timeout since their turn began. Their
+    // opponent is only waiting, so how long they've been quiet doesn't matter.
+    fn check_abandoned(&mut self, room_id: RoomId) {
+        let Some(room) = self.rooms.get_mut(&room_id) else {
+            return;
+        };
+        let ServerState::Playing(session) = &mut room.state else {
+            return;
+        };
+        let game::State::Playing(turn) = session.game.state else {
+            return;
+        };
+        if session.paused {
+            return;
+        }
+
+        // Anything sent before the turn began doesn't count as being around for it
+        let Some(quiet_since) = self
+            .contexts
+            .values()
+            .find(|cx| cx.room == room_id && cx.player() == Some(turn))
+            .map(|cx| cx.last_active.max(session.turn_started))
+        else {
+            return;
+        };
+        if quiet_since.elapsed() <= self.abandon_timeout {
+            return;
+        }
+
+        if let Some(conclusion) = session.game.forfeit(turn) {
+            info!("game abandoned in room {}: {}", room_id, conclusion);
+            room.events.send(Notification::ServerInfo(format!(
+                "{turn} stopped playing, {} wins",
+                !turn
+            )));
+            self.game_concluded(room_id, conclusion);
+        }
+    }
+
+    // Bookkeeping for when a room's game ends, however that happened
+    fn game_concluded(&mut self, room_id: RoomId, conclusion: game::Conclusion) {
+        let Some(room) = self.rooms.get(&room_id) else {
+            return;
+        };
+        let ServerState::Playing(session) = &room.state else {
+            return;
+        };
+        self.scoreboard.record(conclusion, &session.seats);
+        room.events.send(Notification::GameConcluded {
+            conclusion,
+            board: session.game.board.clone(),
+        });
+    }
+
+    // Rooms are made on demand, the first time anyone needs them
+    fn room_mut(&mut self, id: RoomId) -> &mut Room {
+        self.rooms
+            .entry(id)
+            .or_insert_with(|| Room::new(id, self.broadcast.clone()))
+    }
+
+    // The game being played in a room, if there is one
+    fn session_mut(&mut self, room_id: RoomId) -> Option<&mut GameSession> {
+        match &mut self.rooms.get_mut(&room_id)?.state {
+            ServerState::Playing(session) => Some(session),
+            _ => None,
+        }
+    }
+
+    // Tells every room who's in it, at most once per `presence_interval`
+    fn check_presence(&mut self) {
+        let Some(interval) = self.presence_interval else {
+            return;
+        };
+        if self.last_presence.elapsed() < interval {
+            return;
+        }
+        self.last_presence = Instant::now();
+
+        for (&room_id, room) in &self.rooms {
+            let presence: Vec<PresenceEntry> = self
+                .sorted_contexts()
+                .filter(|(_, cx)| cx.room == room_id)
+                .map(|(id, cx)| PresenceEntry {
+                    id,
+                    rtt_ms: None,
+                    idle_ms: cx.last_active.elapsed().as_millis() as u64,
+                })
+                .collect();
+            if !presence.is_empty() {
+                room.events.send(Notification::Presence(presence));
+            }
+        }
+    }
+
+    // Contexts in connection order, anything listing connections to clients
+    // should go through this so the output doesn't depend on HashMap order
+    fn sorted_contexts(&self) -> impl Iterator<Item = (ConnectionId, &ConnectionContext)> {
+        let mut contexts: Vec<_> = self.contexts.iter().map(|(&id, cx)| (id, cx)).collect();
+        contexts.sort_by_key(|&(id, _)| id);
+        contexts.into_iter()
+    }
+
+    // `phase` and the chat figures are for the given room, connections are listed
+    // across all of them
+    fn debug_state(&self, room_id: RoomId) -> ServerDebugState {
+        let room = self.rooms.get(&room_id);
+        let phase = match room.map(|room| &room.state) {
+            Some(ServerState::WaitingForHost) | None => "waiting for host".to_string(),
+            Some(ServerState::WaitingForPlayers) => "waiting for players".to_string(),
+            Some(ServerState::Playing(session)) => format!("playing: {}", session.game.state),
+        };
+
+        ServerDebugState {
+            phase,
+            connections: self
+                .sorted_contexts()
+                .map(|(id, cx)| format!("{} {} room {} {:?}", id, cx.addr, cx.room, cx.group))
+                .collect(),
+            pending_requests: self.req_rx.len(),
+            queued_notifications: self.broadcast.len(),
+            chat_history_bytes: room.map_or(0, |room| room.chat_history.bytes()),
+            chat_evicted: room.map_or(0, |room| room.chat_history.evicted()),
+        }
+    }
+
+    // Sends a notification to a single connection, returns false if there's no
+    // such connection. A full queue drops the notification rather than blocking
+    // the server loop.
+    fn notify(&self, conn_id: ConnectionId, notification: Notification) -> bool {
+        let Some(cx) = self.contexts.get(&conn_id) else {
+            return false;
+        };
+        if let Err(e) = cx.direct.try_send(notification) {
+            warn!("dropping notification for connection {conn_id}: {e}");
+        }
+        true
+    }
+
+    // Back to how things were when the server started, short of dropping
+    // connections. Rooms stay put since connections only learn they've changed
+    // rooms from JoinRoom.
+    fn restart(&mut self) {
+        info!("restarting");
+        for (&id, room) in self.rooms.iter_mut() {
+            *room = Room::new(id, self.broadcast.clone());
+        }
+        for cx in self.contexts.values_mut() {
+            cx.group = match cx.group {
+                Group::Host(_) => Group::Host(None),
+                Group::Player(_) => Group::Observer,
+                group => group,
+            };
+        }
+        self.scoreboard.clear();
+
+        let _ = self.broadcast.send((None, Notification::ServerRestarted));
+        let _ = self.broadcast.send((
+            None,
+            Notification::ServerInfo("server restarted".to_string()),
+        ));
+    }
+
+    // Moves a connection to another room, giving up whatever role it had in the
+    // old one. Whoever opens a room gets to host it, except for the default room
+    // where the host policy decides, the same as for a new connection.
+    fn join_room(&mut self, conn_id: ConnectionId, to: RoomId) -> Result<Response, ErrorResponse> {
+        let Some(mut cx) = self.contexts.remove(&conn_id) else {
+            return Err(ErrorResponse::NoSuchConnection(conn_id));
+        };
+        if cx.room == to {
+            self.contexts.insert(conn_id, cx);
+            return Ok(Response::RoomJoined(to));
+        }
+
+        self.room_mut(cx.room)
+            .events
+            .send(Notification::ServerInfo(format!(
+                "{} left the room",
+                cx.who()
+            )));
+        self.leave_room(&cx);
+
+        let opening = !self.contexts.values().any(|other| other.room == to);
+        cx.group = match to {
+            DEFAULT_ROOM => self.assign_group(cx.addr),
+            _ if opening => Group::Host(None),
+            _ => Group::Observer,
+        };
+        cx.room = to;
+        let who = cx.who();
+        self.contexts.insert(conn_id, cx);
+
+        self.room_mut(to)
+            .events
+            .send(Notification::ServerInfo(format!("{who} joined the room")));
+        self.replay_chat(conn_id, to);
+        Ok(Response::RoomJoined(to))
+    }
+
+    // Catches someone who just came in up on what was being said
+    fn replay_chat(&self, conn_id: ConnectionId, room_id: RoomId) {
+        let Some(room) = self.rooms.get(&room_id) else {
+            return;
+        };
+        let history = room.chat_history.iter();
+        let skipped = history.len().saturating_sub(CHAT_REPLAY);
+        for (from, msg) in history.skip(skipped) {
+            let from = from.to_string();
+            let msg = msg.to_string();
+            self.notify(conn_id, Notification::Chat { from, msg });
+        }
+    }
+
     fn handle_disconnect(&mut self, conn_id: ConnectionId) {
         let cx = self
             .contexts
             .remove(&conn_id)
             .expect("connections cannot be removed twice");
-        println!("client disconnected {:#?}", cx);
-        let _ = self.broadcast.send(Notification::ServerInfo(format!(
-            "{} disconnected",
-            cx.addr
-        )));
+        info!("connection {} ({}) disconnected", conn_id, cx.addr);
+        self.room_mut(cx.room)
+            .events
+            .send(Notification::ServerInfo(format!(
+                "{} disconnected",
+                cx.who()
+            )));
+        self.leave_room(&cx);
+    }
+
+    // Whatever has to happen in a room when a connection leaves it, `cx` is
+    // already out of `contexts`
+    fn leave_room(&mut self, cx: &ConnectionContext) {
+        let room_id = cx.room;
+        let room = self.room_mut(room_id);
+        room.events.send(Notification::PlayerLeft { who: cx.who() });
+
+        // A player leaving mid-game forfeits. Connections without a seat, including
+        // a host that never picked a side, can come and go without affecting the game.
+        if let (ServerState::Playing(session), Some(player)) = (&mut room.state, cx.player()) {
+            if let Some(conclusion) = session.game.forfeit(player) {
+                room.events.send(Notification::ServerInfo(format!(
+                    "{player} left the game, {} wins",
+                    !player
+                )));
+                self.game_concluded(room_id, conclusion);
+            }
+        }
+
+        let mut left_behind = self.contexts.values().filter(|other| other.room == room_id);
+        if room_id != DEFAULT_ROOM && left_behind.clone().next().is_none() {
+            self.rooms.remove(&room_id);
+            return;
+        }
+
+        let anyone_seated = left_behind.any(|other| other.player().is_some());
+        let room = self.room_mut(room_id);
+
+        // With a seat empty there's no rematch, whoever is still seated waits for
+        // someone to take it like before the first game
+        let concluded = matches!(&room.state, ServerState::Playing(session)
+            if matches!(session.game.state, game::State::Concluded(_)));
+        if cx.player().is_some() && concluded {
+            room.state = ServerState::WaitingForPlayers;
+            room.waiting_since = anyone_seated.then(Instant::now);
+        }
+
+        // Without a host or anyone seated the room can't make progress, start
+        // over so the next host can set things up again
+        if cx.is_host() && !anyone_seated && matches!(room.state, ServerState::WaitingForPlayers) {
+            room.state = ServerState::WaitingForHost;
+            room.waiting_since = None;
+        }
     }
 
-    fn handle_new_connection(&mut self, socket: TcpStream, addr: SocketAddr) {
+    // What a new connection starts out as, everyone starts in the default room
+    fn assign_group(&self, addr: SocketAddr) -> Group {
+        let hosts = match self.host_policy {
+            HostPolicy::Loopback => addr.ip().is_loopback(),
+            HostPolicy::FirstToConnect => !self
+                .contexts
+                .values()
+                .any(|cx| cx.room == DEFAULT_ROOM && cx.is_host()),
+            HostPolicy::Anyone => true,
+        };
+        if hosts {
+            Group::Host(None)
+        } else {
+            Group::Observer
+        }
+    }
+
+    fn handle_new_connection(&mut self, socket: TcpStream, addr: SocketAddr, framing: Framing) {
+        if self.contexts.len() >= self.max_connections {
+            warn!("rejecting {addr}, server full");
+            tokio::spawn(async move {
+                let _ = Connection::with_framing(socket, addr, framing)
+                    .send(ErrorResponse::ServerFull)
+                    .await;
+            });
+            return;
+        }
+
         let conn_id = self.next_conn_id;
         self.next_conn_id += 1;
         assert!(
@@ -312,39 +1889,2312 @@ impl Server {
             "connection id already in use"
         );
 
-        let con = Connection::new(socket, addr);
+        let con = Connection::with_framing(socket, addr, framing);
+        let (direct_tx, direct_rx) = mpsc::channel(DIRECT_CAPACITY);
         let handle = ServerHandle {
+            room: DEFAULT_ROOM,
+            all_rooms: false,
+            lagged: false,
             req_tx: self.req_tx.clone(),
             broadcast: self.broadcast.subscribe(),
+            direct: direct_rx,
+            shutting_down: self.shutting_down.subscribe(),
+            idle_timeout: self.idle_timeout,
             conn_id,
         };
 
         let abort_handle = self.connections.spawn(async move {
             let conn_id = handle.conn_id;
-            println!(
-                "connection closed {:#?}",
-                handle_connection(con, handle).await
-            );
+            let addr = con.addr;
+            match handle_connection(con, handle).await {
+                Ok(()) => info!("connection {conn_id} ({addr}) closed"),
+                Err(e) => warn!("connection {conn_id} ({addr}) closed: {e}"),
+            }
             conn_id
         });
 
-        let group = if addr.ip().is_loopback() {
-            Group::Host(None)
-        } else {
-            Group::Observer
-        };
+        self.register(conn_id, addr, framing, abort_handle, direct_tx);
+    }
 
+    // Starts keeping track of a connection whose task is already running
+    fn register(
+        &mut self,
+        conn_id: ConnectionId,
+        addr: SocketAddr,
+        framing: Framing,
+        abort_handle: AbortHandle,
+        direct: mpsc::Sender<Notification>,
+    ) {
+        let group = self.assign_group(addr);
         self.contexts.insert(
             conn_id,
             ConnectionContext {
+                room: DEFAULT_ROOM,
                 group,
                 addr,
+                name: None,
                 abort_handle,
+                direct,
+                last_active: Instant::now(),
+                // Text commands are translated on our end, so they always match
+                peer_protocol: (framing == Framing::Text).then_some(PROTOCOL_VERSION),
+                capabilities: Vec::new(),
             },
         );
+        // Text clients never say hello, see handle_request
+        if framing == Framing::Text {
+            self.replay_chat(conn_id, DEFAULT_ROOM);
+        }
+    }
+}
+
+// Never resolves without a listener, so the branch waiting on it stays quiet
+async fn accept_text(acceptor: &mut Option<Acceptor<TcpListener>>) -> (TcpStream, SocketAddr) {
+    match acceptor {
+        Some(acceptor) => acceptor.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Anything connections can be accepted from, a TcpListener outside of tests
+pub(crate) trait Listen {
+    fn accept(&self) -> impl Future<Output = std::io::Result<(TcpStream, SocketAddr)>> + Send;
+}
+
+impl Listen for TcpListener {
+    fn accept(&self) -> impl Future<Output = std::io::Result<(TcpStream, SocketAddr)>> + Send {
+        TcpListener::accept(self)
+    }
+}
+
+// Accepts connections, riding out errors like running out of fds. After a
+// failure only accepting is held back for a while, the rest of the server loop
+// carries on.
+pub(crate) struct Acceptor<L> {
+    listener: L,
+    // Consecutive failures, the backoff doubles with each one
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl<L: Listen> Acceptor<L> {
+    pub(crate) fn new(listener: L) -> Self {
+        Self {
+            listener,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    // Cancel safe, so it can be raced in select! and picked up again later
+    pub(crate) async fn accept(&mut self) -> (TcpStream, SocketAddr) {
+        loop {
+            if let Some(retry_at) = self.retry_at {
+                sleep_until(retry_at).await;
+                self.retry_at = None;
+            }
+            match self.listener.accept().await {
+                Ok(accepted) => {
+                    self.failures = 0;
+                    return accepted;
+                }
+                Err(e) => {
+                    self.failures = self.failures.saturating_add(1);
+                    let backoff = accept_backoff(self.failures);
+                    warn!("error accepting connection: {e}, retrying in {backoff:?}");
+                    self.retry_at = Some(Instant::now() + backoff);
+                }
+            }
+        }
+    }
+}
+
+// 20ms after the first failure, doubling up to 1.28s
+fn accept_backoff(failures: u32) -> Duration {
+    Duration::from_millis(10 << failures.min(7))
+}
+
+pub async fn run(
+    addr: SocketAddr,
+    text_addr: Option<SocketAddr>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let server = Server::with_addr(addr);
+    match text_addr {
+        Some(text_addr) => server.with_text_addr(text_addr).run(shutdown).await,
+        None => server.run(shutdown).await,
     }
 }
 
-pub async fn run() {
-    _ = Server::default().run().await;
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    // Fails the first `failures_left` accepts the way running out of fds would
+    struct FlakyListener {
+        inner: TcpListener,
+        failures_left: AtomicU32,
+        calls: AtomicU32,
+    }
+
+    impl FlakyListener {
+        async fn new(failures: u32) -> Self {
+            Self {
+                inner: TcpListener::bind("127.0.0.1:0").await.unwrap(),
+                failures_left: AtomicU32::new(failures),
+                calls: AtomicU32::new(0),
+            }
+        }
+    }
+
+    impl Listen for FlakyListener {
+        async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let failing = self
+                .failures_left
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok();
+            if failing {
+                // EMFILE, too many open files
+                return Err(std::io::Error::from_raw_os_error(24));
+            }
+            self.inner.accept().await
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_recovers_after_errors() {
+        let listener = FlakyListener::new(3).await;
+        let addr = listener.inner.local_addr().unwrap();
+        let mut acceptor = Acceptor::new(listener);
+
+        let client = tokio::spawn(TcpStream::connect(addr));
+        let (_, peer) = timeout(Duration::from_secs(5), acceptor.accept())
+            .await
+            .expect("accepts once the errors stop");
+        let client = client.await.unwrap().unwrap();
+
+        assert_eq!(peer, client.local_addr().unwrap());
+        assert_eq!(acceptor.listener.calls.load(Ordering::Relaxed), 4);
+        assert_eq!(acceptor.failures, 0);
+    }
+
+    #[tokio::test]
+    async fn accept_backs_off_without_blocking() {
+        let mut acceptor = Acceptor::new(FlakyListener::new(u32::MAX).await);
+
+        // Anything else the server loop is waiting on still gets its turn
+        select! {
+            _ = acceptor.accept() => panic!("nothing should be accepted"),
+            _ = sleep(Duration::from_millis(50)) => {}
+        }
+        assert!(acceptor.failures >= 1);
+        assert!(acceptor.retry_at.is_some());
+        assert!(acceptor.listener.calls.load(Ordering::Relaxed) < 5);
+    }
+
+    #[test]
+    fn accept_backoff_is_capped() {
+        assert_eq!(accept_backoff(1), Duration::from_millis(20));
+        assert_eq!(accept_backoff(2), Duration::from_millis(40));
+        assert_eq!(accept_backoff(7), Duration::from_millis(1280));
+        assert_eq!(accept_backoff(u32::MAX), Duration::from_millis(1280));
+    }
+
+    fn name_changed(n: usize) -> Notification {
+        Notification::NameChanged {
+            old: format!("player{n}"),
+            new: format!("player{}", n + 1),
+        }
+    }
+
+    #[test]
+    fn outbox_drops_chat_before_game_updates() {
+        let mut outbox = Outbox::default();
+        outbox.push(Notification::Paused(true));
+        for n in 0..100 {
+            outbox.push(Notification::ServerInfo(n.to_string()));
+        }
+
+        assert_eq!(outbox.queue.len(), OUTBOX_CAPACITY);
+        assert!(matches!(outbox.pop(), Some(Notification::Paused(true))));
+        assert!(!outbox.overflowed);
+    }
+
+    #[test]
+    fn outbox_of_a_client_that_never_reads_stays_bounded() {
+        let mut outbox = Outbox::default();
+        for n in 0..10 * OUTBOX_LIMIT {
+            outbox.push(name_changed(n));
+            assert!(outbox.queue.len() <= OUTBOX_LIMIT);
+        }
+        assert!(outbox.overflowed);
+    }
+
+    #[tokio::test]
+    async fn overflowed_outbox_is_resynced_with_the_board() {
+        let (req_tx, mut req_rx) = mpsc::channel::<ContextedRequest>(1);
+        let (_broadcast_tx, broadcast) = broadcast::channel(1);
+        let (_direct_tx, direct) = mpsc::channel(1);
+        let (_shutdown_tx, shutting_down) = watch::channel(false);
+        let mut handle = ServerHandle {
+            conn_id: 1,
+            room: DEFAULT_ROOM,
+            all_rooms: false,
+            lagged: false,
+            req_tx,
+            broadcast,
+            direct,
+            shutting_down,
+            idle_timeout: Duration::from_secs(60),
+        };
+        let game = game::Game::new(game::Player::X);
+        let board = game.board.clone();
+        tokio::spawn(async move {
+            while let Some((_, req, tx)) = req_rx.recv().await {
+                assert!(matches!(req, Request::GetGameInfo));
+                let _ = tx.send(Ok(Response::GameInfo {
+                    game: game.clone(),
+                    moves_left: 9,
+                }));
+            }
+        });
+
+        let mut outbox = Outbox::default();
+        for n in 0..=OUTBOX_LIMIT {
+            outbox.push(name_changed(n));
+        }
+        assert!(outbox.queue.is_empty());
+
+        handle.resync(&mut outbox).await;
+        assert!(!outbox.overflowed);
+        match outbox.pop() {
+            Some(Notification::TurnPlayed { board: b, next }) => {
+                assert_eq!(b, board);
+                assert_eq!(next, Some(game::Player::X));
+            }
+            other => panic!("expected the board, got {other:?}"),
+        }
+        assert!(outbox.pop().is_none());
+
+        // Nothing was missed this time
+        handle.resync(&mut outbox).await;
+        assert!(outbox.pop().is_none());
+    }
+
+    // A connection the server knows about, with no socket behind it
+    struct Client {
+        id: ConnectionId,
+        // Notifications sent to this connection alone
+        direct: mpsc::Receiver<Notification>,
+    }
+
+    // Where the host connects from under the default policy
+    const LOCAL: &str = "127.0.0.1:40000";
+
+    fn remote(n: u8) -> String {
+        format!("192.0.2.{n}:40000")
+    }
+
+    // Registers a connection from `addr` the way accepting it would, and gets
+    // the handshake out of the way. Needs a runtime for the stand-in task.
+    fn connect(server: &mut Server, addr: &str) -> Client {
+        let id = server.next_conn_id;
+        server.next_conn_id += 1;
+        let abort_handle = server.connections.spawn(std::future::pending());
+        let (direct_tx, direct) = mpsc::channel(DIRECT_CAPACITY);
+        server.register(
+            id,
+            addr.parse().unwrap(),
+            Framing::Lines,
+            abort_handle,
+            direct_tx,
+        );
+        let client = Client { id, direct };
+        let welcome = request(
+            server,
+            &client,
+            Request::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+            },
+        );
+        assert!(matches!(welcome, Ok(Response::Welcome { .. })));
+        client
+    }
+
+    fn request(
+        server: &mut Server,
+        client: &Client,
+        req: Request,
+    ) -> Result<Response, ErrorResponse> {
+        let (tx, mut rx) = oneshot::channel();
+        server.handle_request((client.id, req, tx));
+        rx.try_recv().expect("requests are answered right away")
+    }
+
+    fn group(server: &Server, client: &Client) -> Group {
+        server.contexts[&client.id].group
+    }
+
+    #[test]
+    fn loopback_policy_hosts_local_connections() {
+        let server = Server::default().with_host_policy(HostPolicy::Loopback);
+        assert_eq!(
+            server.assign_group(LOCAL.parse().unwrap()),
+            Group::Host(None)
+        );
+        assert_eq!(
+            server.assign_group("[::1]:40000".parse().unwrap()),
+            Group::Host(None)
+        );
+        assert_eq!(
+            server.assign_group(remote(1).parse().unwrap()),
+            Group::Observer
+        );
+    }
+
+    #[tokio::test]
+    async fn first_to_connect_policy_hosts_only_the_first() {
+        for first in [LOCAL.to_string(), remote(1)] {
+            let mut server = Server::default().with_host_policy(HostPolicy::FirstToConnect);
+            let first = connect(&mut server, &first);
+            assert_eq!(group(&server, &first), Group::Host(None));
+            for second in [LOCAL.to_string(), remote(2)] {
+                let second = connect(&mut server, &second);
+                assert_eq!(group(&server, &second), Group::Observer);
+            }
+
+            // Once the host is gone the next one in takes over
+            server.handle_disconnect(first.id);
+            let next = connect(&mut server, &remote(3));
+            assert_eq!(group(&server, &next), Group::Host(None));
+        }
+    }
+
+    #[tokio::test]
+    async fn anyone_policy_hosts_everyone() {
+        let mut server = Server::default().with_host_policy(HostPolicy::Anyone);
+        for addr in [LOCAL.to_string(), remote(1), remote(2)] {
+            let client = connect(&mut server, &addr);
+            assert_eq!(group(&server, &client), Group::Host(None));
+        }
+    }
+
+    #[tokio::test]
+    async fn bot_fills_the_seat_after_the_timeout() {
+        let mut server = Server::default().with_bot_fill(Some(Duration::from_millis(20)));
+        let host = connect(&mut server, LOCAL);
+        request(
+            &mut server,
+            &host,
+            Request::JoinMatch(Some(game::Player::X)),
+        )
+        .unwrap();
+
+        // Not yet
+        server.check_bot_fill(DEFAULT_ROOM);
+        assert!(server.session_mut(DEFAULT_ROOM).is_none());
+
+        sleep(Duration::from_millis(30)).await;
+        server.check_bot_fill(DEFAULT_ROOM);
+        let session = server
+            .session_mut(DEFAULT_ROOM)
+            .expect("a bot game started");
+        assert_eq!(session.bot, Some(game::Player::O));
+        // O goes first, so the bot has already moved
+        assert_eq!(session.game.moves.len(), 1);
+        assert!(matches!(
+            session.game.state,
+            game::State::Playing(game::Player::X)
+        ));
+    }
+
+    #[tokio::test]
+    async fn no_bot_fill_waits_forever() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        request(
+            &mut server,
+            &host,
+            Request::JoinMatch(Some(game::Player::X)),
+        )
+        .unwrap();
+
+        sleep(Duration::from_millis(30)).await;
+        server.check_bot_fill(DEFAULT_ROOM);
+        assert!(matches!(
+            server.rooms[&DEFAULT_ROOM].state,
+            ServerState::WaitingForPlayers
+        ));
+    }
+
+    // A local host playing O against a remote X, with the game under way
+    fn start_game(server: &mut Server) -> (Client, Client) {
+        let o = connect(server, LOCAL);
+        let x = connect(server, &remote(1));
+        request(server, &o, Request::JoinMatch(Some(game::Player::O))).unwrap();
+        let joined = request(server, &x, Request::JoinMatch(None));
+        assert!(matches!(
+            joined,
+            Ok(Response::Joined {
+                side: Some(game::Player::X),
+                starting: true,
+                ..
+            })
+        ));
+        (o, x)
+    }
+
+    fn mark(server: &mut Server, client: &Client, tile: &str) -> Result<Response, ErrorResponse> {
+        let tile: TileId = tile.parse().unwrap();
+        let req = Request::MarkAt {
+            row: tile.rank(),
+            col: tile.file(),
+        };
+        request(server, client, req)
+    }
+
+    fn conclusion(server: &mut Server) -> Option<game::Conclusion> {
+        match server.session_mut(DEFAULT_ROOM)?.game.state {
+            game::State::Concluded(conclusion) => Some(conclusion),
+            game::State::Playing(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn player_who_stalls_forfeits() {
+        let mut server = Server::default().with_abandon_timeout(Duration::from_millis(30));
+        let (o, _x) = start_game(&mut server);
+        mark(&mut server, &o, "b2").unwrap();
+
+        // O is just as quiet while waiting, that doesn't make it a draw
+        sleep(Duration::from_millis(40)).await;
+        server.check_abandoned(DEFAULT_ROOM);
+        assert_eq!(
+            conclusion(&mut server),
+            Some(game::Conclusion::Win(game::Player::O))
+        );
+    }
+
+    #[tokio::test]
+    async fn quiet_player_gets_the_whole_timeout_for_their_turn() {
+        let mut server = Server::default().with_abandon_timeout(Duration::from_millis(30));
+        let (o, _x) = start_game(&mut server);
+
+        // X has said nothing since joining, but only just got the move
+        sleep(Duration::from_millis(40)).await;
+        mark(&mut server, &o, "b2").unwrap();
+        server.check_abandoned(DEFAULT_ROOM);
+        assert_eq!(conclusion(&mut server), None);
+    }
+
+    #[tokio::test]
+    async fn paused_game_is_not_abandoned() {
+        let mut server = Server::default().with_abandon_timeout(Duration::from_millis(30));
+        let (o, _x) = start_game(&mut server);
+        request(&mut server, &o, Request::Pause).unwrap();
+
+        sleep(Duration::from_millis(40)).await;
+        server.check_abandoned(DEFAULT_ROOM);
+        assert_eq!(conclusion(&mut server), None);
+
+        // Resuming starts the wait over
+        request(&mut server, &o, Request::Resume).unwrap();
+        server.check_abandoned(DEFAULT_ROOM);
+        assert_eq!(conclusion(&mut server), None);
+    }
+
+    // Serves on a free local port until the test is over
+    fn spawn_server(server: Server) -> SocketAddr {
+        let listener = server.listen().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.serve(listener, std::future::pending()));
+        addr
+    }
+
+    // A client on the other end of a real socket, past the handshake
+    async fn dial(addr: SocketAddr) -> Connection {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut con = Connection::new(socket, addr);
+        con.request(Request::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        })
+        .await
+        .unwrap();
+        let welcome = recv(&mut con).await;
+        assert!(matches!(
+            welcome,
+            Some(Message::Response(Ok(Response::Welcome { .. })))
+        ));
+        con
+    }
+
+    async fn recv(con: &mut Connection) -> Option<Message> {
+        timeout(Duration::from_secs(5), con.recv())
+            .await
+            .expect("the server answers in time")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_closed() {
+        let server = Server::with_addr("127.0.0.1:0".parse().unwrap())
+            .with_idle_timeout(Duration::from_millis(50));
+        let mut con = dial(spawn_server(server)).await;
+
+        let goodbye = recv(&mut con).await;
+        assert!(matches!(
+            goodbye,
+            Some(Message::Notification(Notification::ServerInfo(msg))) if msg == "closing idle connection"
+        ));
+        assert!(recv(&mut con).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn client_that_stops_reading_is_dropped() {
+        use tokio::io::AsyncWriteExt;
+
+        let server = Server::with_addr("127.0.0.1:0".parse().unwrap())
+            .with_idle_timeout(Duration::from_millis(200));
+        let addr = spawn_server(server);
+        let socket = TcpSocket::new_v4().unwrap();
+        // Small enough that the server's replies back up quickly
+        socket.set_recv_buffer_size(1024).unwrap();
+        let mut stream = socket.connect(addr).await.unwrap();
+        stream
+            .write_all(b"Hello(version:2,capabilities:[])\n")
+            .await
+            .unwrap();
+
+        // Requests keep coming, so the connection is never idle on that front,
+        // but nothing is ever read. Once the writes stall the server gives up
+        // and the next write fails.
+        let flood = async {
+            loop {
+                if stream.write_all(&b"GetVersion\n".repeat(64)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        timeout(Duration::from_secs(10), flood)
+            .await
+            .expect("the server drops the connection");
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_cap_are_turned_away() {
+        let server = Server::with_addr("127.0.0.1:0".parse().unwrap())
+            .with_listen_backlog(16)
+            .with_max_connections(3);
+        let addr = spawn_server(server);
+
+        // All at once, before any of them has said anything
+        let mut sockets = Vec::new();
+        for _ in 0..4 {
+            sockets.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let mut cons: Vec<_> = sockets
+            .into_iter()
+            .map(|socket| Connection::new(socket, addr))
+            .collect();
+        let mut last = cons.pop().unwrap();
+
+        assert!(matches!(
+            recv(&mut last).await,
+            Some(Message::Response(Err(ErrorResponse::ServerFull)))
+        ));
+        assert!(recv(&mut last).await.is_none());
+
+        for con in &mut cons {
+            con.request(Request::GetVersion).await.unwrap();
+            assert!(matches!(
+                recv(con).await,
+                Some(Message::Response(Ok(Response::Version { .. })))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn hopping_rooms_does_not_make_a_remote_client_host() {
+        let mut server = Server::default();
+        let _host = connect(&mut server, LOCAL);
+        let guest = connect(&mut server, &remote(1));
+        assert_eq!(group(&server, &guest), Group::Observer);
+
+        // Opening a room of its own makes it host there
+        assert!(matches!(
+            request(&mut server, &guest, Request::JoinRoom(99)),
+            Ok(Response::RoomJoined(99))
+        ));
+        assert_eq!(group(&server, &guest), Group::Host(None));
+
+        // But not once it's back
+        assert!(matches!(
+            request(&mut server, &guest, Request::JoinRoom(DEFAULT_ROOM)),
+            Ok(Response::RoomJoined(DEFAULT_ROOM))
+        ));
+        assert_eq!(group(&server, &guest), Group::Observer);
+        for req in [
+            Request::ClearScoreboard,
+            Request::SetObserverChat(false),
+            Request::TransferHost(guest.id),
+            Request::SetBoardSize { n: 4, k: 4 },
+        ] {
+            assert!(matches!(
+                request(&mut server, &guest, req),
+                Err(ErrorResponse::NotAllowed)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn loopback_host_stays_host_in_the_default_room() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let _guest = connect(&mut server, &remote(1));
+
+        // Someone is already in room 0 when the host comes back, it's the host
+        // policy that makes it host again rather than opening the room
+        request(&mut server, &host, Request::JoinRoom(5)).unwrap();
+        request(&mut server, &host, Request::JoinRoom(DEFAULT_ROOM)).unwrap();
+        assert_eq!(group(&server, &host), Group::Host(None));
+    }
+
+    #[tokio::test]
+    async fn joining_someone_elses_room_makes_an_observer() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let guest = connect(&mut server, &remote(1));
+
+        request(&mut server, &guest, Request::JoinRoom(7)).unwrap();
+        request(&mut server, &host, Request::JoinRoom(7)).unwrap();
+        assert_eq!(group(&server, &guest), Group::Host(None));
+        assert_eq!(group(&server, &host), Group::Observer);
+    }
+
+    fn whisper(to: &Client) -> Request {
+        Request::Whisper {
+            to: to.id,
+            msg: "psst".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn whispers_reach_only_their_target() {
+        let mut server = Server::default();
+        let mut host = connect(&mut server, LOCAL);
+        let mut a = connect(&mut server, &remote(1));
+        let mut b = connect(&mut server, &remote(2));
+
+        request(&mut server, &a, whisper(&b)).unwrap();
+        assert!(matches!(
+            b.direct.try_recv(),
+            Ok(Notification::Whisper { msg, .. }) if msg == "psst"
+        ));
+        assert!(a.direct.try_recv().is_err());
+        assert!(host.direct.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn muted_observers_cannot_whisper() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let observer = connect(&mut server, &remote(1));
+        request(&mut server, &host, Request::SetObserverChat(false)).unwrap();
+
+        assert!(matches!(
+            request(&mut server, &observer, whisper(&host)),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        // The host still can
+        request(&mut server, &host, whisper(&observer)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn whispers_do_not_cross_rooms() {
+        let mut server = Server::default();
+        let mut host = connect(&mut server, LOCAL);
+        let guest = connect(&mut server, &remote(1));
+        request(&mut server, &guest, Request::JoinRoom(7)).unwrap();
+
+        assert!(matches!(
+            request(&mut server, &guest, whisper(&host)),
+            Err(ErrorResponse::NoSuchConnection(id)) if id == host.id
+        ));
+        assert!(host.direct.try_recv().is_err());
+    }
+
+    // O takes the a file
+    fn o_wins(server: &mut Server, o: &Client, x: &Client) {
+        for (client, tile) in [(o, "a1"), (x, "b1"), (o, "a2"), (x, "b2"), (o, "a3")] {
+            mark(server, client, tile).unwrap();
+        }
+        assert_eq!(
+            conclusion(server),
+            Some(game::Conclusion::Win(game::Player::O))
+        );
+    }
+
+    #[tokio::test]
+    async fn rematch_lets_the_loser_go_first() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        o_wins(&mut server, &o, &x);
+
+        request(&mut server, &x, Request::Rematch).unwrap();
+        let session = server.session_mut(DEFAULT_ROOM).unwrap();
+        assert!(session.game.moves.is_empty());
+        assert!(matches!(
+            session.game.state,
+            game::State::Playing(game::Player::X)
+        ));
+    }
+
+    #[tokio::test]
+    async fn leaving_a_finished_game_frees_the_seat() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        let observer = connect(&mut server, &remote(2));
+        o_wins(&mut server, &o, &x);
+
+        server.handle_disconnect(x.id);
+        let room = &server.rooms[&DEFAULT_ROOM];
+        assert!(matches!(room.state, ServerState::WaitingForPlayers));
+        assert!(room.waiting_since.is_some());
+        assert!(matches!(
+            request(&mut server, &o, Request::Rematch),
+            Err(ErrorResponse::NotAllowed)
+        ));
+
+        assert!(matches!(
+            request(&mut server, &observer, Request::JoinMatch(None)),
+            Ok(Response::Joined {
+                side: Some(game::Player::X),
+                starting: true,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn room_starts_over_once_both_players_leave() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        let observer = connect(&mut server, &remote(2));
+        o_wins(&mut server, &o, &x);
+
+        server.handle_disconnect(x.id);
+        server.handle_disconnect(o.id);
+        assert!(matches!(
+            server.rooms[&DEFAULT_ROOM].state,
+            ServerState::WaitingForHost
+        ));
+
+        // The next host sets up a game the observer can join
+        let host = connect(&mut server, LOCAL);
+        request(
+            &mut server,
+            &host,
+            Request::JoinMatch(Some(game::Player::O)),
+        )
+        .unwrap();
+        assert!(matches!(
+            request(&mut server, &observer, Request::JoinMatch(None)),
+            Ok(Response::Joined {
+                side: Some(game::Player::X),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn move_tokens_need_the_capability() {
+        let mut server = Server::default();
+        let (o, _x) = start_game(&mut server);
+        let play = Request::PlayTurnWithToken { tile: 4, token: 1 };
+
+        assert!(matches!(
+            request(&mut server, &o, play.clone()),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        assert!(server
+            .session_mut(DEFAULT_ROOM)
+            .unwrap()
+            .game
+            .moves
+            .is_empty());
+
+        // Only what the server knows about is agreed on
+        let welcome = request(
+            &mut server,
+            &o,
+            Request::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: vec!["move-tokens".to_string(), "teleport".to_string()],
+            },
+        );
+        assert!(matches!(
+            welcome,
+            Ok(Response::Welcome { capabilities, .. }) if capabilities == ["move-tokens"]
+        ));
+
+        request(&mut server, &o, play.clone()).unwrap();
+        assert_eq!(
+            server.session_mut(DEFAULT_ROOM).unwrap().game.moves.len(),
+            1
+        );
+        assert!(matches!(
+            request(&mut server, &o, play),
+            Ok(Response::DuplicateMove(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn joining_names_the_opponent_without_their_role() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        request(&mut server, &host, Request::SetName("alice".to_string())).unwrap();
+        assert!(matches!(
+            request(
+                &mut server,
+                &host,
+                Request::JoinMatch(Some(game::Player::O))
+            ),
+            Ok(Response::Joined {
+                opponent: None,
+                starting: false,
+                ..
+            })
+        ));
+
+        let guest = connect(&mut server, &remote(1));
+        assert!(matches!(
+            request(&mut server, &guest, Request::JoinMatch(None)),
+            Ok(Response::Joined {
+                opponent: Some(name),
+                starting: true,
+                ..
+            }) if name == "alice"
+        ));
+    }
+
+    #[tokio::test]
+    async fn first_to_sit_down_has_no_opponent() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        request(&mut server, &host, Request::JoinMatch(None)).unwrap();
+
+        let first = connect(&mut server, &remote(1));
+        assert!(matches!(
+            request(&mut server, &first, Request::JoinMatch(None)),
+            Ok(Response::Joined {
+                side: Some(game::Player::O),
+                opponent: None,
+                starting: false,
+            })
+        ));
+        let second = connect(&mut server, &remote(2));
+        assert!(matches!(
+            request(&mut server, &second, Request::JoinMatch(None)),
+            Ok(Response::Joined {
+                side: Some(game::Player::X),
+                opponent: Some(name),
+                starting: true,
+            }) if name == remote(1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn draw_can_be_claimed_once_no_one_can_win() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        // X O X
+        // O O X
+        // - X O
+        for (client, tile) in [
+            (&o, 1),
+            (&x, 0),
+            (&o, 3),
+            (&x, 2),
+            (&o, 4),
+            (&x, 5),
+            (&o, 8),
+            (&x, 7),
+        ] {
+            request(&mut server, client, Request::PlayTurn(tile)).unwrap();
+        }
+
+        assert!(matches!(
+            request(&mut server, &o, Request::ClaimDraw),
+            Ok(Response::GameConcluded(game::Conclusion::Draw))
+        ));
+        assert_eq!(conclusion(&mut server), Some(game::Conclusion::Draw));
+    }
+
+    #[tokio::test]
+    async fn draw_cannot_be_claimed_while_a_line_is_open() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        request(&mut server, &o, Request::PlayTurn(4)).unwrap();
+
+        assert!(matches!(
+            request(&mut server, &x, Request::ClaimDraw),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        assert_eq!(conclusion(&mut server), None);
+    }
+
+    fn chat(server: &mut Server, client: &Client, msg: &str) {
+        request(server, client, Request::Chat(msg.to_string())).unwrap();
+    }
+
+    fn replayed(client: &mut Client) -> Vec<String> {
+        let mut msgs = Vec::new();
+        while let Ok(notification) = client.direct.try_recv() {
+            if let Notification::Chat { msg, .. } = notification {
+                msgs.push(msg);
+            }
+        }
+        msgs
+    }
+
+    #[tokio::test]
+    async fn chat_is_replayed_to_newcomers() {
+        let mut server = Server::default();
+        let mut host = connect(&mut server, LOCAL);
+        chat(&mut server, &host, "hi");
+        chat(&mut server, &host, "anyone?");
+        assert!(replayed(&mut host).is_empty());
+
+        let mut guest = connect(&mut server, &remote(1));
+        assert_eq!(replayed(&mut guest), ["hi", "anyone?"]);
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        };
+        request(&mut server, &guest, hello).unwrap();
+        assert!(replayed(&mut guest).is_empty());
+
+        // Each room has its own
+        request(&mut server, &guest, Request::JoinRoom(3)).unwrap();
+        assert!(replayed(&mut guest).is_empty());
+        chat(&mut server, &guest, "quiet here");
+        request(&mut server, &host, Request::JoinRoom(3)).unwrap();
+        assert_eq!(replayed(&mut host), ["quiet here"]);
+    }
+
+    #[tokio::test]
+    async fn only_recent_chat_is_replayed() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        for n in 0..2 * CHAT_REPLAY {
+            chat(&mut server, &host, &n.to_string());
+        }
+
+        let mut guest = connect(&mut server, &remote(1));
+        let expected: Vec<_> = (CHAT_REPLAY..2 * CHAT_REPLAY)
+            .map(|n| n.to_string())
+            .collect();
+        assert_eq!(replayed(&mut guest), expected);
+    }
+
+    // Everything broadcast from here on, to any room
+    fn events(server: &Server) -> broadcast::Receiver<Broadcast> {
+        server.broadcast.subscribe()
+    }
+
+    fn drain(events: &mut broadcast::Receiver<Broadcast>) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        while let Ok((_, notification)) = events.try_recv() {
+            notifications.push(notification);
+        }
+        notifications
+    }
+
+    #[tokio::test]
+    async fn only_the_host_sets_the_turn_time_limit_before_the_game() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let guest = connect(&mut server, &remote(1));
+        let mut events = events(&server);
+        let limit = Some(Duration::from_secs(20));
+
+        assert!(matches!(
+            request(&mut server, &guest, Request::SetTurnTimeLimit(limit)),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        request(&mut server, &host, Request::SetTurnTimeLimit(limit)).unwrap();
+        assert!(drain(&mut events)
+            .iter()
+            .any(|n| matches!(n, Notification::TurnTimeLimit(l) if *l == limit)));
+
+        request(
+            &mut server,
+            &host,
+            Request::JoinMatch(Some(game::Player::O)),
+        )
+        .unwrap();
+        request(&mut server, &guest, Request::JoinMatch(None)).unwrap();
+        assert_eq!(
+            server.session_mut(DEFAULT_ROOM).unwrap().turn_time_limit,
+            limit
+        );
+        assert!(matches!(
+            request(&mut server, &host, Request::GetTurnDeadline),
+            Ok(Response::TurnDeadline {
+                player: game::Player::O,
+                ms_remaining,
+            }) if ms_remaining <= 20_000 && ms_remaining > 19_000
+        ));
+        assert!(matches!(
+            request(&mut server, &host, Request::SetTurnTimeLimit(None)),
+            Err(ErrorResponse::MatchInProgress)
+        ));
+    }
+
+    #[tokio::test]
+    async fn host_can_mute_observer_chat() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let observer = connect(&mut server, &remote(1));
+        let say = || Request::Chat("gg".to_string());
+
+        request(&mut server, &observer, say()).unwrap();
+        assert!(matches!(
+            request(&mut server, &observer, Request::SetObserverChat(false)),
+            Err(ErrorResponse::NotAllowed)
+        ));
+
+        request(&mut server, &host, Request::SetObserverChat(false)).unwrap();
+        assert!(matches!(
+            request(&mut server, &observer, say()),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        request(&mut server, &host, say()).unwrap();
+
+        request(&mut server, &host, Request::SetObserverChat(true)).unwrap();
+        request(&mut server, &observer, say()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn observers_see_the_board_a_disconnect_forfeits() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        mark(&mut server, &o, "b2").unwrap();
+        mark(&mut server, &x, "a1").unwrap();
+        let expected = server.session_mut(DEFAULT_ROOM).unwrap().game.board.clone();
+        let mut events = events(&server);
+
+        server.handle_disconnect(x.id);
+        let concluded = drain(&mut events).into_iter().find_map(|n| match n {
+            Notification::GameConcluded { conclusion, board } => Some((conclusion, board)),
+            _ => None,
+        });
+        assert_eq!(
+            concluded,
+            Some((game::Conclusion::Win(game::Player::O), expected))
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_agrees_on_the_shared_capabilities() {
+        let mut server = Server::default();
+        let client = connect(&mut server, LOCAL);
+        let id = client.id;
+        assert!(server.contexts[&id].capabilities.is_empty());
+
+        let hello = |capabilities: &[&str]| Request::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        };
+        assert!(matches!(
+            request(&mut server, &client, hello(&["compression", "move-tokens"])),
+            Ok(Response::Welcome { version: PROTOCOL_VERSION, capabilities })
+                if capabilities == ["move-tokens"]
+        ));
+        assert!(server.contexts[&id].has_capability("move-tokens"));
+        assert!(!server.contexts[&id].has_capability("compression"));
+
+        // Saying hello again starts over
+        assert!(matches!(
+            request(&mut server, &client, hello(&["compression"])),
+            Ok(Response::Welcome { capabilities, .. }) if capabilities.is_empty()
+        ));
+        assert!(!server.contexts[&id].has_capability("move-tokens"));
+    }
+
+    #[tokio::test]
+    async fn notify_reaches_exactly_one_connection() {
+        let mut server = Server::default();
+        let mut clients: Vec<_> = (1..=3).map(|n| connect(&mut server, &remote(n))).collect();
+
+        assert!(server.notify(clients[1].id, Notification::Paused(true)));
+        assert!(clients[0].direct.try_recv().is_err());
+        assert!(matches!(
+            clients[1].direct.try_recv(),
+            Ok(Notification::Paused(true))
+        ));
+        assert!(clients[2].direct.try_recv().is_err());
+
+        assert!(!server.notify(999, Notification::Paused(true)));
+    }
+
+    #[tokio::test]
+    async fn version_is_the_compiled_one_even_before_hello() {
+        let mut server = Server::default();
+        let abort_handle = server.connections.spawn(std::future::pending());
+        let (direct_tx, direct) = mpsc::channel(DIRECT_CAPACITY);
+        server.register(
+            1,
+            LOCAL.parse().unwrap(),
+            Framing::Lines,
+            abort_handle,
+            direct_tx,
+        );
+        let client = Client { id: 1, direct };
+
+        let version = request(&mut server, &client, Request::GetVersion);
+        assert!(matches!(
+            version,
+            Ok(Response::Version { crate_version, protocol_version: PROTOCOL_VERSION })
+                if crate_version == env!("CARGO_PKG_VERSION")
+        ));
+        // Unlike anything else
+        assert!(matches!(
+            request(&mut server, &client, Request::ListPlayers),
+            Err(ErrorResponse::InvalidMessage(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn resent_moves_are_recognised_by_their_token() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec!["move-tokens".to_string()],
+        };
+        request(&mut server, &o, hello).unwrap();
+        let play = |tile, token| Request::PlayTurnWithToken { tile, token };
+
+        request(&mut server, &o, play(4, 7)).unwrap();
+        // The ack got lost, so O sends it again
+        assert!(matches!(
+            request(&mut server, &o, play(4, 7)),
+            Ok(Response::DuplicateMove(7))
+        ));
+        assert_eq!(
+            server.session_mut(DEFAULT_ROOM).unwrap().game.moves.len(),
+            1
+        );
+
+        // Someone else going for the same tile is told who has it
+        assert!(matches!(
+            request(&mut server, &x, Request::PlayTurn(4)),
+            Err(ErrorResponse::TileOccupied { tile, by: game::Player::O }) if tile == TileId::B2
+        ));
+        request(&mut server, &x, Request::PlayTurn(0)).unwrap();
+
+        // A new token is a new move
+        request(&mut server, &o, play(8, 8)).unwrap();
+        assert_eq!(
+            server.session_mut(DEFAULT_ROOM).unwrap().game.moves.len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn session_keeps_each_players_clock() {
+        let bank = TimeBank {
+            initial: Duration::from_secs(60),
+            increment: Duration::ZERO,
+        };
+        let limit = Duration::from_secs(10);
+        let seats = HashMap::from([
+            (game::Player::O, "alice".to_string()),
+            (game::Player::X, "bob".to_string()),
+        ]);
+        let mut session = GameSession::new(
+            game::Game::new(game::Player::O),
+            seats,
+            Some(limit),
+            Some(bank),
+        );
+        assert_eq!(session.time_left(game::Player::X), Some(bank.initial));
+        assert!(session.deadline(game::Player::O).unwrap() <= limit);
+        // Only the player on turn has a turn limit
+        assert_eq!(session.deadline(game::Player::X), Some(bank.initial));
+
+        sleep(Duration::from_millis(20)).await;
+        session.stop_clock();
+        session.game.play(TileId::B2, game::Player::O).unwrap();
+        session.start_turn();
+
+        assert!(session.clocks[&game::Player::O] <= bank.initial - Duration::from_millis(20));
+        assert_eq!(session.clocks[&game::Player::X], bank.initial);
+        assert_eq!(session.turn_used, Duration::ZERO);
+        let left = session.deadline(game::Player::X).unwrap();
+        assert!(left <= limit && left > limit - Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn starting_a_game_seats_both_players() {
+        let mut server = Server::default();
+        assert!(server.session_mut(DEFAULT_ROOM).is_none());
+        let (o, _x) = start_game(&mut server);
+        request(&mut server, &o, Request::SetName("alice".to_string())).unwrap();
+
+        // Seats are taken as the game starts, renaming later doesn't move them
+        let session = server.session_mut(DEFAULT_ROOM).unwrap();
+        assert_eq!(session.seats[&game::Player::O], LOCAL);
+        assert_eq!(session.seats[&game::Player::X], remote(1));
+        assert!(session.bot.is_none());
+        assert!(!session.paused);
+    }
+
+    fn turn_ms_left(server: &mut Server, client: &Client) -> u64 {
+        match request(server, client, Request::GetTurnDeadline) {
+            Ok(Response::TurnDeadline { ms_remaining, .. }) => ms_remaining,
+            other => panic!("expected a deadline, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn players_pause_together_and_the_clock_stops() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        request(
+            &mut server,
+            &host,
+            Request::SetTurnTimeLimit(Some(Duration::from_secs(10))),
+        )
+        .unwrap();
+        request(&mut server, &host, Request::JoinMatch(None)).unwrap();
+        let o = connect(&mut server, &remote(1));
+        let x = connect(&mut server, &remote(2));
+        let observer = connect(&mut server, &remote(3));
+        request(&mut server, &o, Request::JoinMatch(None)).unwrap();
+        request(&mut server, &x, Request::JoinMatch(None)).unwrap();
+        let mut events = events(&server);
+
+        assert!(matches!(
+            request(&mut server, &observer, Request::Pause),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        // One player asking isn't enough
+        request(&mut server, &x, Request::Pause).unwrap();
+        assert!(!server.session_mut(DEFAULT_ROOM).unwrap().paused);
+        request(&mut server, &o, Request::Pause).unwrap();
+        assert!(server.session_mut(DEFAULT_ROOM).unwrap().paused);
+        assert!(drain(&mut events)
+            .iter()
+            .any(|n| matches!(n, Notification::Paused(true))));
+
+        let left = turn_ms_left(&mut server, &o);
+        sleep(Duration::from_millis(30)).await;
+        assert_eq!(turn_ms_left(&mut server, &o), left);
+        assert!(matches!(
+            mark(&mut server, &o, "b2"),
+            Err(ErrorResponse::NotAllowed)
+        ));
+
+        // Either player can resume
+        request(&mut server, &x, Request::Resume).unwrap();
+        assert!(drain(&mut events)
+            .iter()
+            .any(|n| matches!(n, Notification::Paused(false))));
+        mark(&mut server, &o, "b2").unwrap();
+    }
+
+    #[tokio::test]
+    async fn host_pauses_alone() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        request(&mut server, &o, Request::Pause).unwrap();
+        assert!(server.session_mut(DEFAULT_ROOM).unwrap().paused);
+        assert!(matches!(
+            mark(&mut server, &o, "b2"),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        request(&mut server, &x, Request::Resume).unwrap();
+        mark(&mut server, &o, "b2").unwrap();
+    }
+
+    #[tokio::test]
+    async fn mark_at_takes_row_and_column() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        request(&mut server, &o, Request::MarkAt { row: 0, col: 0 }).unwrap();
+        request(&mut server, &x, Request::MarkAt { row: 2, col: 2 }).unwrap();
+        request(&mut server, &o, Request::MarkAt { row: 0, col: 2 }).unwrap();
+
+        let moves = &server.session_mut(DEFAULT_ROOM).unwrap().game.moves;
+        assert_eq!(
+            moves[..],
+            [
+                (game::Player::O, TileId::A1),
+                (game::Player::X, TileId::C3),
+                (game::Player::O, TileId::C1),
+            ]
+        );
+
+        for (row, col) in [(3, 0), (0, 3), (u8::MAX, u8::MAX)] {
+            assert!(matches!(
+                request(&mut server, &x, Request::MarkAt { row, col }),
+                Err(ErrorResponse::InvalidParam(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn match_result_is_there_once_the_game_is_over() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        assert!(matches!(
+            request(&mut server, &host, Request::GetMatchResult),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        server.handle_disconnect(host.id);
+
+        let (o, x) = start_game(&mut server);
+        assert!(matches!(
+            request(&mut server, &x, Request::GetMatchResult),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        o_wins(&mut server, &o, &x);
+
+        // Someone who only just came in
+        let late = connect(&mut server, &remote(2));
+        match request(&mut server, &late, Request::GetMatchResult) {
+            Ok(Response::MatchResult {
+                conclusion,
+                final_board,
+                winning_line,
+            }) => {
+                assert_eq!(conclusion, game::Conclusion::Win(game::Player::O));
+                assert_eq!(final_board.to_compact(), "OX-OX-O--");
+                assert_eq!(winning_line, Some(vec![TileId::A1, TileId::A2, TileId::A3]));
+            }
+            other => panic!("expected the result, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn moves_after_the_end_learn_the_outcome() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        o_wins(&mut server, &o, &x);
+
+        for (client, tile) in [(&x, "c3"), (&o, "c3"), (&x, "a1")] {
+            assert!(matches!(
+                mark(&mut server, client, tile),
+                Err(ErrorResponse::GameConcluded(game::Conclusion::Win(
+                    game::Player::O
+                )))
+            ));
+        }
+        assert_eq!(
+            server.session_mut(DEFAULT_ROOM).unwrap().game.moves.len(),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn each_move_is_announced_with_its_tile() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        let mut events = events(&server);
+
+        mark(&mut server, &o, "b2").unwrap();
+        mark(&mut server, &x, "c1").unwrap();
+        let placed: Vec<_> = drain(&mut events)
+            .into_iter()
+            .filter_map(|n| match n {
+                Notification::TilePlaced {
+                    player,
+                    tile,
+                    time_left,
+                } => Some((player, tile, time_left)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                (game::Player::O, TileId::B2, None),
+                (game::Player::X, TileId::C1, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn taking_the_hosts_side_is_refused() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let guest = connect(&mut server, &remote(1));
+        request(
+            &mut server,
+            &host,
+            Request::JoinMatch(Some(game::Player::X)),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            request(&mut server, &guest, Request::JoinMatch(Some(game::Player::X))),
+            Err(ErrorResponse::InvalidParam(msg)) if msg == "side X is taken"
+        ));
+        assert_eq!(group(&server, &guest), Group::Observer);
+
+        assert!(matches!(
+            request(
+                &mut server,
+                &guest,
+                Request::JoinMatch(Some(game::Player::O))
+            ),
+            Ok(Response::Joined {
+                side: Some(game::Player::O),
+                starting: true,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn hosting_only_connection_can_leave_mid_game() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        request(&mut server, &host, Request::JoinMatch(None)).unwrap();
+        let o = connect(&mut server, &remote(1));
+        let x = connect(&mut server, &remote(2));
+        request(&mut server, &o, Request::JoinMatch(None)).unwrap();
+        request(&mut server, &x, Request::JoinMatch(None)).unwrap();
+        mark(&mut server, &o, "b2").unwrap();
+
+        server.handle_disconnect(host.id);
+        assert_eq!(conclusion(&mut server), None);
+        mark(&mut server, &x, "a1").unwrap();
+        mark(&mut server, &o, "c3").unwrap();
+        assert_eq!(
+            server.session_mut(DEFAULT_ROOM).unwrap().game.moves.len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn host_can_hand_over_to_someone_else() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        let observer = connect(&mut server, &remote(2));
+
+        assert!(matches!(
+            request(&mut server, &x, Request::TransferHost(x.id)),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        assert!(matches!(
+            request(&mut server, &o, Request::TransferHost(999)),
+            Err(ErrorResponse::NoSuchConnection(999))
+        ));
+
+        // Both keep their seats
+        request(&mut server, &o, Request::TransferHost(x.id)).unwrap();
+        assert_eq!(group(&server, &o), Group::Player(game::Player::O));
+        assert_eq!(group(&server, &x), Group::Host(Some(game::Player::X)));
+        request(&mut server, &x, Request::SetObserverChat(false)).unwrap();
+        assert!(matches!(
+            request(&mut server, &o, Request::SetObserverChat(true)),
+            Err(ErrorResponse::NotAllowed)
+        ));
+
+        request(&mut server, &x, Request::TransferHost(observer.id)).unwrap();
+        assert_eq!(group(&server, &observer), Group::Host(None));
+        assert_eq!(group(&server, &x), Group::Player(game::Player::X));
+    }
+
+    #[tokio::test]
+    async fn preview_leaves_the_game_alone() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        mark(&mut server, &o, "a1").unwrap();
+        let before = server.session_mut(DEFAULT_ROOM).unwrap().game.clone();
+        let mut events = events(&server);
+
+        match request(&mut server, &x, Request::PreviewMove(4)) {
+            Ok(Response::Preview(board)) => assert_eq!(board.to_compact(), "O---X----"),
+            other => panic!("expected a preview, got {other:?}"),
+        }
+        let after = &server.session_mut(DEFAULT_ROOM).unwrap().game;
+        assert_eq!(after.board, before.board);
+        assert_eq!(after.moves, before.moves);
+        assert!(matches!(after.state, game::State::Playing(game::Player::X)));
+        assert!(drain(&mut events).is_empty());
+
+        assert!(matches!(
+            request(&mut server, &x, Request::PreviewMove(0)),
+            Err(ErrorResponse::TileOccupied { .. })
+        ));
+        assert!(matches!(
+            request(&mut server, &o, Request::PreviewMove(4)),
+            Err(ErrorResponse::NotYourTurn {
+                turn: game::Player::X
+            })
+        ));
+        assert!(matches!(
+            request(&mut server, &x, Request::PreviewMove(9)),
+            Err(ErrorResponse::InvalidTile)
+        ));
+    }
+
+    fn stats(server: &mut Server, client: &Client) -> (u32, u32, u32, u32) {
+        match request(server, client, Request::GetMyStats) {
+            Ok(Response::PlayerStats {
+                wins,
+                losses,
+                draws,
+                games,
+                ..
+            }) => (wins, losses, draws, games),
+            other => panic!("expected stats, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_follow_the_games_played() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        assert_eq!(stats(&mut server, &o), (0, 0, 0, 0));
+        assert!(matches!(
+            request(&mut server, &o, Request::GetMyStats),
+            Ok(Response::PlayerStats { win_rate, .. }) if win_rate == 0.0
+        ));
+
+        o_wins(&mut server, &o, &x);
+        request(&mut server, &x, Request::Rematch).unwrap();
+        // X goes first this time and takes the a file
+        for (client, tile) in [(&x, "a1"), (&o, "b1"), (&x, "a2"), (&o, "b2"), (&x, "a3")] {
+            mark(&mut server, client, tile).unwrap();
+        }
+
+        assert_eq!(stats(&mut server, &o), (1, 1, 0, 2));
+        assert_eq!(stats(&mut server, &x), (1, 1, 0, 2));
+        let observer = connect(&mut server, &remote(2));
+        assert_eq!(stats(&mut server, &observer), (0, 0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn observers_are_told_to_join_before_playing() {
+        let mut server = Server::default();
+        let (_o, _x) = start_game(&mut server);
+        let observer = connect(&mut server, &remote(2));
+
+        for req in [
+            Request::PlayTurn(4),
+            Request::MarkAt { row: 1, col: 1 },
+            Request::PreviewMove(4),
+        ] {
+            assert!(matches!(
+                request(&mut server, &observer, req),
+                Err(ErrorResponse::NotSeated)
+            ));
+        }
+        assert!(server
+            .session_mut(DEFAULT_ROOM)
+            .unwrap()
+            .game
+            .moves
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn debug_state_is_only_for_loopback() {
+        // Hosting doesn't come into it
+        let mut server = Server::default().with_host_policy(HostPolicy::Anyone);
+        let local = connect(&mut server, LOCAL);
+        let guest = connect(&mut server, &remote(1));
+        assert_eq!(group(&server, &guest), Group::Host(None));
+
+        assert!(matches!(
+            request(&mut server, &guest, Request::DebugState),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        chat(&mut server, &local, "hi");
+        match request(&mut server, &local, Request::DebugState) {
+            Ok(Response::DebugState(state)) => {
+                assert_eq!(state.connections.len(), 2);
+                assert_eq!(state.chat_history_bytes, "host".len() + "hi".len());
+            }
+            other => panic!("expected the state, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn quick_joiners_get_opposite_sides() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        request(&mut server, &host, Request::JoinMatch(None)).unwrap();
+        let first = connect(&mut server, &remote(1));
+        let second = connect(&mut server, &remote(2));
+
+        let side = |joined| match joined {
+            Ok(Response::Joined { side, .. }) => side,
+            other => panic!("expected to join, got {other:?}"),
+        };
+        let a = side(request(&mut server, &first, Request::QuickJoin));
+        let b = side(request(&mut server, &second, Request::QuickJoin));
+        assert!(a.is_some() && b.is_some());
+        assert_ne!(a, b);
+        assert!(server.session_mut(DEFAULT_ROOM).is_some());
+    }
+
+    #[tokio::test]
+    async fn quick_joining_host_gets_o() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        request(&mut server, &host, Request::QuickJoin).unwrap();
+        assert_eq!(group(&server, &host), Group::Host(Some(game::Player::O)));
+    }
+
+    fn spectators(server: &mut Server, client: &Client) -> u32 {
+        match request(server, client, Request::SpectatorCount) {
+            Ok(Response::SpectatorCount(n)) => n,
+            other => panic!("expected a count, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn spectator_count_leaves_out_the_players() {
+        let mut server = Server::default();
+        let (o, _x) = start_game(&mut server);
+        assert_eq!(spectators(&mut server, &o), 0);
+
+        let watchers: Vec<_> = (2..5).map(|n| connect(&mut server, &remote(n))).collect();
+        assert_eq!(spectators(&mut server, &o), 3);
+        assert_eq!(spectators(&mut server, &watchers[0]), 3);
+
+        // Only this room's
+        request(&mut server, &watchers[1], Request::JoinRoom(9)).unwrap();
+        server.handle_disconnect(watchers[2].id);
+        assert_eq!(spectators(&mut server, &o), 1);
+    }
+
+    #[tokio::test]
+    async fn players_are_listed_in_connection_order() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let mut ids = vec![host.id];
+        for n in 1..8 {
+            ids.push(connect(&mut server, &remote(n)).id);
+        }
+
+        for _ in 0..5 {
+            match request(&mut server, &host, Request::ListPlayers) {
+                Ok(Response::Roster(roster)) => {
+                    let listed: Vec<_> = roster.iter().map(|entry| entry.id).collect();
+                    assert_eq!(listed, ids);
+                    assert_eq!(roster[0].group, Group::Host(None));
+                    assert_eq!(roster[1].name, remote(1));
+                }
+                other => panic!("expected the roster, got {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_moves_say_why() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+
+        assert!(matches!(
+            mark(&mut server, &x, "b2"),
+            Err(ErrorResponse::NotYourTurn {
+                turn: game::Player::O
+            })
+        ));
+        mark(&mut server, &o, "b2").unwrap();
+        assert!(matches!(
+            mark(&mut server, &o, "a1"),
+            Err(ErrorResponse::NotYourTurn {
+                turn: game::Player::X
+            })
+        ));
+        assert!(matches!(
+            mark(&mut server, &x, "b2"),
+            Err(ErrorResponse::TileOccupied {
+                tile: TileId::B2,
+                by: game::Player::O
+            })
+        ));
+        assert!(matches!(
+            request(&mut server, &x, Request::PlayTurn(9)),
+            Err(ErrorResponse::InvalidTile)
+        ));
+    }
+
+    #[tokio::test]
+    async fn host_leaving_an_empty_lobby_starts_it_over() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let observer = connect(&mut server, &remote(1));
+        request(&mut server, &host, Request::JoinMatch(None)).unwrap();
+        assert!(matches!(
+            server.rooms[&DEFAULT_ROOM].state,
+            ServerState::WaitingForPlayers
+        ));
+
+        server.handle_disconnect(host.id);
+        assert!(matches!(
+            server.rooms[&DEFAULT_ROOM].state,
+            ServerState::WaitingForHost
+        ));
+        assert!(matches!(
+            request(&mut server, &observer, Request::JoinMatch(None)),
+            Err(ErrorResponse::WaitingForHost)
+        ));
+
+        // Someone already seated keeps the lobby open
+        let host = connect(&mut server, LOCAL);
+        request(&mut server, &host, Request::JoinMatch(None)).unwrap();
+        request(&mut server, &observer, Request::JoinMatch(None)).unwrap();
+        server.handle_disconnect(host.id);
+        assert!(matches!(
+            server.rooms[&DEFAULT_ROOM].state,
+            ServerState::WaitingForPlayers
+        ));
+    }
+
+    #[tokio::test]
+    async fn rage_quit_hands_the_win_to_the_other_player() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        mark(&mut server, &o, "b2").unwrap();
+        let mut events = events(&server);
+
+        server.handle_disconnect(o.id);
+        assert!(drain(&mut events).iter().any(|notification| matches!(
+            notification,
+            Notification::GameConcluded {
+                conclusion: game::Conclusion::Win(game::Player::X),
+                ..
+            }
+        )));
+        assert_eq!(stats(&mut server, &x), (1, 0, 0, 1));
+        // X keeps their seat and waits for a new opponent
+        assert!(matches!(
+            server.room_mut(DEFAULT_ROOM).state,
+            ServerState::WaitingForPlayers
+        ));
+    }
+
+    #[tokio::test]
+    async fn leaving_after_the_end_is_not_another_loss() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        o_wins(&mut server, &o, &x);
+        let mut events = events(&server);
+
+        server.handle_disconnect(x.id);
+        assert!(!drain(&mut events)
+            .iter()
+            .any(|notification| matches!(notification, Notification::GameConcluded { .. })));
+        assert_eq!(stats(&mut server, &o), (1, 0, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn board_can_be_rewound_to_any_move() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        for (client, tile) in [(&o, "b2"), (&x, "a1"), (&o, "c3"), (&x, "a3")] {
+            mark(&mut server, client, tile).unwrap();
+        }
+
+        let mut expected = game::Board::new(3);
+        expected.mark(TileId::B2, game::Player::O);
+        expected.mark(TileId::A1, game::Player::X);
+        expected.mark(TileId::C3, game::Player::O);
+        assert!(matches!(
+            request(&mut server, &x, Request::GetBoardAt(3)),
+            Ok(Response::BoardInfo(board)) if board == expected
+        ));
+        assert!(matches!(
+            request(&mut server, &x, Request::GetBoardAt(0)),
+            Ok(Response::BoardInfo(board)) if board == game::Board::new(3)
+        ));
+        assert!(matches!(
+            request(&mut server, &x, Request::GetBoardAt(5)),
+            Err(ErrorResponse::InvalidParam(_))
+        ));
+    }
+
+    fn turn_changes(events: &mut broadcast::Receiver<Broadcast>) -> Vec<game::Player> {
+        drain(events)
+            .into_iter()
+            .filter_map(|notification| match notification {
+                Notification::TurnChanged { now_playing } => Some(now_playing),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn turn_changes_are_announced_after_each_move() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        let mut events = events(&server);
+
+        mark(&mut server, &o, "a1").unwrap();
+        assert_eq!(turn_changes(&mut events), [game::Player::X]);
+        mark(&mut server, &x, "b1").unwrap();
+        assert_eq!(turn_changes(&mut events), [game::Player::O]);
+
+        // Rejected moves don't pass the turn
+        assert!(mark(&mut server, &x, "c1").is_err());
+        assert!(turn_changes(&mut events).is_empty());
+
+        // Nor does the move that ends the game
+        for (client, tile) in [(&o, "a2"), (&x, "b2"), (&o, "a3")] {
+            mark(&mut server, client, tile).unwrap();
+        }
+        assert_eq!(
+            turn_changes(&mut events),
+            [game::Player::X, game::Player::O]
+        );
+    }
+
+    #[tokio::test]
+    async fn host_can_clear_the_scoreboard() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        o_wins(&mut server, &o, &x);
+        let mut events = events(&server);
+
+        assert!(matches!(
+            request(&mut server, &x, Request::ClearScoreboard),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        assert_eq!(stats(&mut server, &x), (0, 1, 0, 1));
+
+        request(&mut server, &o, Request::ClearScoreboard).unwrap();
+        assert!(matches!(
+            drain(&mut events)[..],
+            [Notification::ScoreboardCleared]
+        ));
+        assert_eq!(stats(&mut server, &o), (0, 0, 0, 0));
+        assert_eq!(stats(&mut server, &x), (0, 0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn host_picks_the_board_size_before_the_match() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        let guest = connect(&mut server, &remote(1));
+
+        for (n, k) in [(2, 2), (MAX_BOARD_SIZE + 1, 3), (5, 6), (5, 2)] {
+            assert!(
+                matches!(
+                    request(&mut server, &host, Request::SetBoardSize { n, k }),
+                    Err(ErrorResponse::InvalidParam(_))
+                ),
+                "{n}×{n} board with {k} in a row"
+            );
+        }
+        assert!(matches!(
+            request(&mut server, &guest, Request::SetBoardSize { n: 4, k: 3 }),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        for (n, k) in [(3, 3), (MAX_BOARD_SIZE, MAX_BOARD_SIZE), (4, 3)] {
+            request(&mut server, &host, Request::SetBoardSize { n, k }).unwrap();
+        }
+
+        // The last size set is the one the match is played on
+        request(
+            &mut server,
+            &host,
+            Request::JoinMatch(Some(game::Player::O)),
+        )
+        .unwrap();
+        request(&mut server, &guest, Request::JoinMatch(None)).unwrap();
+        assert!(matches!(
+            request(&mut server, &guest, Request::GetGameInfo),
+            Ok(Response::GameInfo { game, .. }) if game.board.size() == 4 && game.win_len == 3
+        ));
+        assert!(matches!(
+            request(&mut server, &host, Request::SetBoardSize { n: 3, k: 3 }),
+            Err(ErrorResponse::MatchInProgress)
+        ));
+    }
+
+    #[tokio::test]
+    async fn winning_move_ends_the_game_right_away() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        for (client, tile) in [(&o, "a1"), (&x, "b1"), (&o, "a2"), (&x, "b2")] {
+            mark(&mut server, client, tile).unwrap();
+        }
+        assert!(matches!(
+            mark(&mut server, &o, "a3"),
+            Ok(Response::GameConcluded(game::Conclusion::Win(
+                game::Player::O
+            )))
+        ));
+
+        // Neither side gets another move in, whoever's turn it would have been
+        for (client, tile) in [(&x, "b3"), (&o, "c3")] {
+            assert!(matches!(
+                mark(&mut server, client, tile),
+                Err(ErrorResponse::GameConcluded(game::Conclusion::Win(
+                    game::Player::O
+                )))
+            ));
+        }
+        assert!(matches!(
+            request(&mut server, &x, Request::GetGameInfo),
+            Ok(Response::GameInfo { game, .. }) if game.board.mark_count() == 5
+        ));
+    }
+
+    #[tokio::test]
+    async fn notifications_with_no_one_listening_are_dropped() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        // No connection tasks are running, so nothing is subscribed
+        assert_eq!(server.broadcast.receiver_count(), 0);
+
+        chat(&mut server, &o, "anyone there?");
+        o_wins(&mut server, &o, &x);
+        request(&mut server, &o, Request::ClearScoreboard).unwrap();
+        server.handle_disconnect(x.id);
+        server.handle_disconnect(o.id);
+    }
+
+    #[tokio::test]
+    async fn timestamped_pings_are_echoed() {
+        let mut server = Server::default();
+        let client = connect(&mut server, &remote(1));
+        let now = || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64
+        };
+
+        let before = now();
+        let pong = request(&mut server, &client, Request::PingTs(before));
+        let after = now();
+        assert!(matches!(
+            pong,
+            Ok(Response::PongTs { client_ts, server_ts })
+                if client_ts == before && (before..=after).contains(&server_ts)
+        ));
+
+        // Whatever the client's clock says comes back untouched
+        assert!(matches!(
+            request(&mut server, &client, Request::PingTs(u64::MAX)),
+            Ok(Response::PongTs {
+                client_ts: u64::MAX,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn turn_deadline_counts_down() {
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        assert!(matches!(
+            request(&mut server, &host, Request::GetTurnDeadline),
+            Err(ErrorResponse::NotAllowed)
+        ));
+
+        // A game without a time limit has no deadline either
+        let (_, x) = start_game(&mut server);
+        assert!(matches!(
+            request(&mut server, &x, Request::GetTurnDeadline),
+            Err(ErrorResponse::NotAllowed)
+        ));
+
+        let mut server = Server::default();
+        let host = connect(&mut server, LOCAL);
+        request(
+            &mut server,
+            &host,
+            Request::SetTurnTimeLimit(Some(Duration::from_secs(10))),
+        )
+        .unwrap();
+        let (o, x) = start_game(&mut server);
+        let start = turn_ms_left(&mut server, &x);
+        sleep(Duration::from_millis(50)).await;
+        let later = turn_ms_left(&mut server, &x);
+        assert!(later + 50 <= start, "{later}ms left, {start}ms before");
+
+        // The next player starts on a fresh clock
+        mark(&mut server, &o, "b2").unwrap();
+        assert!(matches!(
+            request(&mut server, &o, Request::GetTurnDeadline),
+            Ok(Response::TurnDeadline {
+                player: game::Player::X,
+                ms_remaining,
+            }) if ms_remaining > later
+        ));
+    }
+
+    // Sends a request and waits for the answer, skipping the notifications on
+    // the way
+    async fn ask(con: &mut Connection, req: Request) -> Result<Response, ErrorResponse> {
+        con.request(req).await.unwrap();
+        loop {
+            match recv(con).await {
+                Some(Message::Response(rsp)) => return rsp,
+                Some(_) => {}
+                None => panic!("connection closed before answering"),
+            }
+        }
+    }
+
+    // Reads notifications until one matches
+    async fn wait_for(con: &mut Connection, wanted: impl Fn(&Notification) -> bool) {
+        loop {
+            match recv(con).await {
+                Some(Message::Notification(notification)) if wanted(&notification) => return,
+                Some(_) => {}
+                None => panic!("connection closed while waiting"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_hear_everything_but_take_no_part() {
+        let addr = spawn_server(Server::with_addr("127.0.0.1:0".parse().unwrap()));
+        let mut o = dial(addr).await;
+        let mut x = dial(addr).await;
+        let mut other_room = dial(addr).await;
+        let mut subscriber = dial(addr).await;
+        assert!(matches!(
+            ask(&mut subscriber, Request::SubscribeAll).await,
+            Ok(Response::Subscribed)
+        ));
+
+        ask(&mut o, Request::JoinMatch(Some(game::Player::O)))
+            .await
+            .unwrap();
+        ask(&mut x, Request::JoinMatch(None)).await.unwrap();
+        ask(&mut o, Request::MarkAt { row: 1, col: 1 })
+            .await
+            .unwrap();
+        wait_for(&mut subscriber, |n| {
+            matches!(
+                n,
+                Notification::TilePlaced {
+                    tile: TileId::B2,
+                    ..
+                }
+            )
+        })
+        .await;
+
+        // Rooms it never joined included
+        ask(&mut other_room, Request::JoinRoom(7)).await.unwrap();
+        ask(&mut other_room, Request::Chat("over here".to_string()))
+            .await
+            .unwrap();
+        wait_for(
+            &mut subscriber,
+            |n| matches!(n, Notification::Chat { msg, .. } if msg == "over here"),
+        )
+        .await;
+
+        assert!(matches!(
+            ask(&mut subscriber, Request::MarkAt { row: 0, col: 0 }).await,
+            Err(ErrorResponse::NotSeated)
+        ));
+        for req in [
+            Request::JoinMatch(None),
+            Request::Chat("hi".to_string()),
+            Request::JoinRoom(7),
+        ] {
+            assert!(matches!(
+                ask(&mut subscriber, req).await,
+                Err(ErrorResponse::NotAllowed)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn restart_puts_every_room_back_to_the_start() {
+        let mut server = Server::default();
+        let (o, x) = start_game(&mut server);
+        mark(&mut server, &o, "b2").unwrap();
+        let elsewhere = connect(&mut server, &remote(2));
+        request(&mut server, &elsewhere, Request::JoinRoom(3)).unwrap();
+        let mut events = events(&server);
+
+        assert!(matches!(
+            request(&mut server, &x, Request::RestartServer),
+            Err(ErrorResponse::NotAllowed)
+        ));
+        assert!(conclusion(&mut server).is_none());
+
+        request(&mut server, &o, Request::RestartServer).unwrap();
+        for room in [DEFAULT_ROOM, 3] {
+            assert!(matches!(
+                server.room_mut(room).state,
+                ServerState::WaitingForHost
+            ));
+        }
+        assert!(drain(&mut events)
+            .iter()
+            .any(|n| matches!(n, Notification::ServerRestarted)));
+
+        // Everyone is still connected, just no longer seated
+        assert_eq!(group(&server, &o), Group::Host(None));
+        assert_eq!(group(&server, &x), Group::Observer);
+        assert_eq!(group(&server, &elsewhere), Group::Host(None));
+        assert!(matches!(
+            request(&mut server, &x, Request::GetGameInfo),
+            Err(ErrorResponse::NotAllowed)
+        ));
+    }
+
+    // One side of a conversation over the text port
+    struct Typist {
+        lines: tokio::io::Lines<tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>>,
+        writer: tokio::net::tcp::OwnedWriteHalf,
+    }
+
+    impl Typist {
+        async fn dial(addr: SocketAddr) -> Self {
+            use tokio::io::AsyncBufReadExt;
+
+            // The text port is bound once the server gets going
+            let socket = timeout(Duration::from_secs(5), async {
+                loop {
+                    match TcpStream::connect(addr).await {
+                        Ok(socket) => break socket,
+                        Err(_) => sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            })
+            .await
+            .expect("the text port opens");
+            let (reader, writer) = socket.into_split();
+            Self {
+                lines: tokio::io::BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+
+        async fn say(&mut self, command: &str) {
+            use tokio::io::AsyncWriteExt;
+
+            self.writer
+                .write_all(format!("{command}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        // Reads until a line with `wanted` in it shows up
+        async fn expect(&mut self, wanted: &str) {
+            loop {
+                let line = timeout(Duration::from_secs(5), self.lines.next_line())
+                    .await
+                    .unwrap_or_else(|_| panic!("never got {wanted:?}"))
+                    .unwrap();
+                match line {
+                    Some(line) if line.contains(wanted) => return,
+                    Some(_) => {}
+                    None => panic!("connection closed before {wanted:?}"),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn match_can_be_played_over_text_commands() {
+        // Any free port, the text listener can't be handed over like the main one
+        let text_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = Server::with_addr("127.0.0.1:0".parse().unwrap()).with_text_addr(text_addr);
+        spawn_server(server);
+
+        let mut o = Typist::dial(text_addr).await;
+        let mut x = Typist::dial(text_addr).await;
+        o.say("join o").await;
+        o.expect("joined as O").await;
+        x.say("join").await;
+        x.expect("joined as X").await;
+
+        x.say("play nowhere").await;
+        x.expect("error: unknown tile").await;
+        o.say("play a1").await;
+        x.expect("X to move").await;
+        x.say("play b1").await;
+        o.expect("O to move").await;
+        o.say("play middle-left").await;
+        x.expect("X to move").await;
+        x.say("play center").await;
+        o.expect("O to move").await;
+        x.say("chat good luck").await;
+        o.expect("good luck").await;
+
+        o.say("play top-left").await;
+        o.expect("O wins!").await;
+        x.expect("O wins!").await;
+        x.say("quit").await;
+        o.expect("left").await;
+    }
+
+    // Idle times from the Presence notifications sent so far, by connection
+    fn idle_times(events: &mut broadcast::Receiver<Broadcast>) -> Vec<Vec<(ConnectionId, u64)>> {
+        drain(events)
+            .into_iter()
+            .filter_map(|notification| match notification {
+                Notification::Presence(entries) => Some(
+                    entries
+                        .into_iter()
+                        .map(|entry| (entry.id, entry.idle_ms))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn stale_connections_show_growing_idle_time() {
+        let mut server = Server::default().with_presence_interval(Some(Duration::from_millis(20)));
+        let stale = connect(&mut server, LOCAL);
+        let active = connect(&mut server, &remote(1));
+        let mut events = events(&server);
+
+        let mut stale_idle = 0;
+        for _ in 0..2 {
+            sleep(Duration::from_millis(30)).await;
+            request(&mut server, &active, Request::GetVersion).unwrap();
+            server.check_presence();
+            // Too soon for another one
+            server.check_presence();
+
+            let sent = idle_times(&mut events);
+            assert_eq!(sent.len(), 1);
+            let [(stale_id, now_idle), (active_id, active_idle)] = sent[0][..] else {
+                panic!("expected both connections, got {:?}", sent[0]);
+            };
+            assert_eq!((stale_id, active_id), (stale.id, active.id));
+            assert!(
+                now_idle >= stale_idle + 30,
+                "{now_idle}ms after {stale_idle}ms"
+            );
+            assert!(active_idle < 30);
+            stale_idle = now_idle;
+        }
+
+        let mut server = server.with_presence_interval(None);
+        sleep(Duration::from_millis(30)).await;
+        server.check_presence();
+        assert!(idle_times(&mut events).is_empty());
+    }
 }
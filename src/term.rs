@@ -1,24 +1,35 @@
-use crate::game::*;
+use crate::{
+    connection::Connection,
+    game::*,
+    message::{Message, Notification, Request, Response, PROTOCOL_VERSION},
+};
 use std::{
     io::{self, Write},
+    net::SocketAddr,
     str::FromStr,
+    thread,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpStream,
+    select,
 };
 
-pub fn play(first_turn: Player) {
+// With `confirm_moves` set, each move is previewed and has to be confirmed
+// before it's played
+pub fn play(first_turn: Player, confirm_moves: bool) {
     let mut game = Game::new(first_turn);
     let stdin = io::stdin();
     let mut stdout = io::stdout();
     let mut input = String::new();
 
-    let mut prompt_for_tile = |turn| loop {
+    let mut prompt = |prompt: &str| {
         input.clear();
-        print!("{}'s turn: ", turn);
+        print!("{}", prompt);
         stdout.flush().expect("this should not fail");
         stdin.read_line(&mut input).expect("stdio read fucked");
-        if let Ok(tile) = TileId::from_str(input.trim()) {
-            return tile;
-        }
-        println!("Invalid input! Try again.");
+        input.trim().to_string()
     };
 
     let conclusion = loop {
@@ -28,20 +39,228 @@ pub fn play(first_turn: Player) {
                 println!("\n{}\n", game.board);
 
                 loop {
-                    let tile = prompt_for_tile(whos_turn);
-                    if game.try_turn(tile) {
-                        break;
+                    let tile = match TileId::from_str(&prompt(&format!("{}'s turn: ", whos_turn))) {
+                        Ok(tile) => tile,
+                        Err(e) => {
+                            println!("Invalid input, {e}. Try something like b2 or top-left.");
+                            continue;
+                        }
+                    };
+
+                    if confirm_moves {
+                        let mut preview = game.clone();
+                        if preview.try_turn(tile) {
+                            println!("\n{}\n", preview.board.render_with_last(Some(tile)));
+                            if !prompt("Play this move? [y/n] ").eq_ignore_ascii_case("y") {
+                                continue;
+                            }
+                        }
+                    }
+
+                    match game.play(tile, whos_turn) {
+                        Ok(()) => break,
+                        Err(e) => println!("Invalid tile, {e}! Try again."),
                     }
-                    println!("Invalid tile! Tile already marked. Try again.");
                 }
-                game.next_turn();
             }
         };
     };
 
     match conclusion {
-        Conclusion::Win(player) => println!("{player} won!"),
-        Conclusion::Draw => println!("Draw."),
+        Conclusion::Draw if game.is_cats_game() => println!("Cat's game."),
+        _ => println!("{conclusion}"),
+    }
+    println!("\n{}\n", final_board(&game));
+}
+
+// The board as the game ended, with the winning line in lowercase
+fn final_board(game: &Game) -> String {
+    let winning_line = match game.state {
+        State::Concluded(Conclusion::Win(player)) => game.winning_lines_for(player).concat(),
+        _ => Vec::new(),
     };
+    game.board.render_highlighted(&winning_line)
+}
+
+// Plays back a list of moves locally, waiting `speed` between each one. Stops
+// early at the first illegal move and returns the game as it ended up.
+pub fn watch_replay(first_turn: Player, moves: &[TileId], speed: Duration) -> Game {
+    let mut game = Game::new(first_turn);
     println!("\n{}\n", game.board);
+
+    for &tile in moves {
+        thread::sleep(speed);
+        let State::Playing(turn) = game.state else {
+            println!("Game already over, stopping.");
+            break;
+        };
+        if let Err(e) = game.play(tile, turn) {
+            println!("Illegal move in replay, {e}, stopping.");
+            break;
+        }
+        println!("\n{}\n", game.board.render_with_last(Some(tile)));
+    }
+
+    if let State::Concluded(conclusion) = game.state {
+        println!("{conclusion}");
+    }
+    game
+}
+
+// Plays on a server instead of locally. Board updates and chat are printed as
+// they come in while moves are read from stdin.
+pub async fn play_online(addr: SocketAddr) -> anyhow::Result<()> {
+    let mut con = Connection::new(TcpStream::connect(addr).await?, addr);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    // The last board the server sent, for showing the new game after a rematch
+    let mut board = Board::default();
+
+    con.request(Request::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: Vec::new(),
+    })
+    .await?;
+    con.request(Request::QuickJoin).await?;
+    con.request(Request::GetGameInfo).await?;
+    println!("Type a tile to play it (b2, center, ...), /say <message> to chat or /quit to leave.");
+
+    loop {
+        select! {
+            msg = con.recv::<Message>() => match msg? {
+                Some(msg) => show(msg, &mut board),
+                None => {
+                    println!("Server closed the connection.");
+                    break;
+                }
+            },
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    con.request(Request::Disconnect).await?;
+                    break;
+                };
+                let req = match line.trim() {
+                    "" => continue,
+                    "/quit" => {
+                        con.request(Request::Disconnect).await?;
+                        break;
+                    }
+                    line if line.starts_with("/say ") => Request::Chat(line["/say ".len()..].to_string()),
+                    // The server knows the board size, let it turn the tile into an index
+                    line => match TileId::from_str(line) {
+                        Ok(tile) => Request::MarkAt {
+                            row: tile.rank(),
+                            col: tile.file(),
+                        },
+                        Err(e) => {
+                            println!("Invalid input, {e}. Try something like b2 or top-left.");
+                            continue;
+                        }
+                    },
+                };
+                con.request(req).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn show(msg: Message, board: &mut Board) {
+    match msg {
+        Message::Response(Ok(Response::Joined {
+            side: Some(side), ..
+        })) => {
+            println!("Joined as {side}.")
+        }
+        Message::Response(Ok(Response::Joined { side: None, .. })) => println!("Watching."),
+        Message::Response(Ok(Response::GameInfo { game, .. })) => {
+            *board = game.board;
+            println!("\n{board}\n");
+        }
+        // The board gets printed when TurnPlayed comes in
+        Message::Response(Ok(Response::TurnDone(game))) => *board = game.board,
+        Message::Response(Ok(
+            Response::Ack | Response::Welcome { .. } | Response::GameConcluded(_),
+        )) => {}
+        Message::Response(Ok(rsp)) => println!("{rsp:?}"),
+        Message::Response(Err(e)) => println!("Error: {e:?}"),
+        Message::Notification(Notification::TurnPlayed { board: new, next }) => {
+            *board = new;
+            println!("\n{board}\n");
+            if let Some(next) = next {
+                println!("{next}'s turn.");
+            }
+        }
+        Message::Notification(Notification::GameConcluded {
+            conclusion,
+            board: new,
+        }) => {
+            *board = new;
+            println!("{conclusion}");
+        }
+        Message::Notification(Notification::BoardSize { n, .. }) => *board = Board::new(n),
+        Message::Notification(Notification::NewGame { first_turn }) => {
+            *board = Board::new(board.size());
+            println!("New game, {first_turn} goes first.\n\n{board}\n");
+        }
+        Message::Notification(Notification::Chat { from, msg }) => println!("{from}: {msg}"),
+        Message::Notification(Notification::Whisper { from, msg }) => {
+            println!("{from} (whisper): {msg}")
+        }
+        Message::Notification(Notification::ServerInfo(info)) => println!("* {info}"),
+        // Everything else is either covered by the above or not interesting to a person
+        Message::Notification(_) | Message::Request(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_ends_on_the_final_board() {
+        let moves = [TileId::B2, TileId::A1, TileId::C3, TileId::C1, TileId::B1];
+        let game = watch_replay(Player::O, &moves, Duration::ZERO);
+        assert_eq!(game.board.to_compact(), "XOX-O---O");
+        assert_eq!(game.moves.len(), moves.len());
+        assert!(matches!(game.state, State::Playing(Player::X)));
+    }
+
+    #[test]
+    fn replay_stops_at_an_illegal_move() {
+        let moves = [TileId::B2, TileId::B2, TileId::A1];
+        let game = watch_replay(Player::X, &moves, Duration::ZERO);
+        assert_eq!(game.board.to_compact(), "----X----");
+        assert_eq!(game.moves, [(Player::X, TileId::B2)]);
+    }
+
+    #[test]
+    fn final_board_marks_the_winning_line() {
+        let moves = [TileId::A1, TileId::B1, TileId::A2, TileId::B2, TileId::A3];
+        let game = watch_replay(Player::O, &moves, Duration::ZERO);
+        assert_eq!(
+            final_board(&game),
+            "3│ o--\n2│ oX-\n1│ oX-\n ╰─────\n   ABC"
+        );
+    }
+
+    #[test]
+    fn final_board_of_a_draw_is_plain() {
+        // X O X
+        // X O O
+        // O X X
+        let moves = [
+            TileId::A3,
+            TileId::B2,
+            TileId::C3,
+            TileId::B3,
+            TileId::B1,
+            TileId::A1,
+            TileId::C1,
+            TileId::C2,
+            TileId::A2,
+        ];
+        let game = watch_replay(Player::X, &moves, Duration::ZERO);
+        assert!(game.is_cats_game());
+        assert_eq!(final_board(&game), game.board.to_string());
+    }
 }
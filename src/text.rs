@@ -0,0 +1,96 @@
+// Plain text front end for people playing over nc or telnet. Each line typed is
+// turned into a Request, and everything sent back is rendered as text instead
+// of RON.
+//
+//  join [x|o]    take a seat, any free one if no side is given
+//  play <tile>   mark a tile, e.g. b2 or center
+//  chat <msg>    say something to the room
+//  name <name>   go by something other than our address
+//  board         show the board
+//  resign, rematch, quit
+use crate::{
+    game::{Player, TileId},
+    message::{Error, Message, Notification, Request, Response},
+};
+
+pub const USAGE: &str =
+    "commands: join [x|o], play <tile>, chat <msg>, name <name>, board, resign, rematch, quit";
+
+pub fn parse_command(line: &str) -> Result<Request, String> {
+    let line = line.trim();
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.trim();
+    match (command.to_ascii_lowercase().as_str(), arg) {
+        ("join", "") => Ok(Request::QuickJoin),
+        ("join", side) => match side.to_ascii_lowercase().as_str() {
+            "o" => Ok(Request::JoinMatch(Some(Player::O))),
+            "x" => Ok(Request::JoinMatch(Some(Player::X))),
+            _ => Err(format!("no side {side:?}, pick x or o")),
+        },
+        ("play", tile) => {
+            let tile = tile.parse::<TileId>().map_err(|e| e.to_string())?;
+            Ok(Request::MarkAt {
+                row: tile.rank(),
+                col: tile.file(),
+            })
+        }
+        ("chat", "") => Err("nothing to say".to_string()),
+        ("chat", msg) => Ok(Request::Chat(msg.to_string())),
+        ("name", "") => Err("no name given".to_string()),
+        ("name", name) => Ok(Request::SetName(name.to_string())),
+        ("board", "") => Ok(Request::GetGameInfo),
+        ("resign", "") => Ok(Request::Resign),
+        ("rematch", "") => Ok(Request::Rematch),
+        ("quit", "") => Ok(Request::Disconnect),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+// What to show a person for a message, None for the ones only a program
+// would care about
+pub fn describe(msg: &Message) -> Option<String> {
+    let text = match msg {
+        Message::Response(Ok(rsp)) => match rsp {
+            Response::Ack => "ok".to_string(),
+            Response::Joined {
+                side: Some(side), ..
+            } => format!("joined as {side}"),
+            Response::Joined { side: None, .. } => "watching".to_string(),
+            Response::GameInfo { game, .. } => format!("{}\n{}", game.board, game.state),
+            // The TurnPlayed that follows shows the board
+            Response::TurnDone(_) | Response::GameConcluded(_) => return None,
+            rsp => format!("{rsp:?}"),
+        },
+        Message::Response(Err(e)) => match e {
+            Error::InvalidMessage(reason) | Error::InvalidParam(reason) => {
+                format!("error: {reason}")
+            }
+            e => format!("error: {e:?}"),
+        },
+        Message::Notification(notification) => match notification {
+            Notification::Chat { from, msg } => format!("{from}: {msg}"),
+            Notification::Whisper { from, msg } => format!("{from} (whisper): {msg}"),
+            Notification::ServerInfo(info) => format!("* {info}"),
+            Notification::PlayerJoined {
+                who,
+                as_player: Some(side),
+            } => format!("* {who} joined as {side}"),
+            Notification::PlayerLeft { who } => format!("* {who} left"),
+            Notification::NameChanged { old, new } => format!("* {old} is now {new}"),
+            Notification::TurnPlayed { board, next } => match next {
+                Some(next) => format!("{board}\n{next} to move"),
+                None => board.to_string(),
+            },
+            Notification::GameConcluded { conclusion, .. } => conclusion.to_string(),
+            Notification::NewGame { first_turn } => {
+                format!("* new game, {first_turn} goes first")
+            }
+            Notification::TimeWarning { player, remaining } => {
+                format!("* {player} has {}s left", remaining.as_secs())
+            }
+            _ => return None,
+        },
+        Message::Request(_) => return None,
+    };
+    Some(text)
+}